@@ -1,10 +1,33 @@
 extern crate raytracer;
 use raytracer::vec3::*;
+use raytracer::camera::Camera;
+use raytracer::filter::Filter;
+use raytracer::RenderOptions;
+use raytracer::error::Result;
+use raytracer::framebuffer::Framebuffer;
+use raytracer::integrator;
+use raytracer::tonemap::{ToneMapping, Operator};
 use raytracer::solids::sphere::Sphere;
 use raytracer::solids::triangle::Triangle;
 use raytracer::solids::Object;
+use raytracer::scene::Scene;
 use std::env::args;
 use std::process::exit;
+use std::str::FromStr;
+
+type RenderFn = fn(&Scene, &RenderOptions) -> Result<Framebuffer>;
+
+fn parse_vec3(opt: &str, val: Option<String>) -> Vec3<f64> {
+    let val = match val {
+        None => {eprintln!("Option --{} requires a value", opt); exit(1);},
+        Some(v) => v
+    };
+    // Accept both "x y z" and "x,y,z"
+    match Vec3::from_str(&val.replace(',', " ")) {
+        Ok(v) => v,
+        Err(e) => {eprintln!("Invalid {} value: {}", opt, e); exit(1);}
+    }
+}
 
 fn main() {
     let default: Vec<Object> = vec![
@@ -29,12 +52,20 @@ fn main() {
                         Vec3::new(1., -3.5, -18.)
                     )))
     ];
-    let mut spheres = default;
+    let mut scene = Scene::new(default, Camera::default());
 
     let mut out_name = "out.png".to_string();
     let mut width = 1280;
     let mut height = 720;
-    let mut func: Box<fn(usize, usize, &Vec<Object>, &str)> = Box::new(raytracer::render);
+    let mut func: RenderFn = raytracer::render;
+    let mut spp = 1;
+    let mut filter = Filter::Box;
+    let mut integrator = integrator::by_name("whitted").unwrap();
+    let mut tone_mapping = ToneMapping::default();
+    let mut eye = None;
+    let mut target = None;
+    let mut up = None;
+    let mut fov = None;
 
     let mut names: Vec<String> = Vec::with_capacity(2);
     let mut ar = args().skip(1);
@@ -48,7 +79,54 @@ fn main() {
             // Long option names
             Some(ref s) if s.starts_with("--") => {
                 match &s[2..s.len()] {
-                    "wireframe" => {func = Box::new(raytracer::render_wireframe);}
+                    "wireframe" => {func = raytracer::render_wireframe;}
+                    "eye" => {eye = Some(parse_vec3("eye", ar.next()));}
+                    "look-at" => {target = Some(parse_vec3("look-at", ar.next()));}
+                    "up" => {up = Some(parse_vec3("up", ar.next()));}
+                    "spp" => {
+                        spp = match ar.next().map(|st| st.parse::<usize>()) {
+                            None => {eprintln!("Option --spp requires a value"); exit(1);},
+                            Some(Ok(v)) if v > 0 => v,
+                            _ => {eprintln!("Invalid spp value"); exit(1);}
+                        };
+                    },
+                    "filter" => {
+                        filter = match ar.next().map(|st| st.parse::<Filter>()) {
+                            None => {eprintln!("Option --filter requires a value"); exit(1);},
+                            Some(Ok(f)) => f,
+                            Some(Err(e)) => {eprintln!("{}", e); exit(1);}
+                        };
+                    },
+                    "integrator" => {
+                        integrator = match ar.next().map(|st| integrator::by_name(&st)) {
+                            None => {eprintln!("Option --integrator requires a value"); exit(1);},
+                            Some(Some(i)) => i,
+                            _ => {eprintln!("Invalid integrator, expected one of whitted, path, \
+                                            ao, normals, depth"); exit(1);}
+                        };
+                    },
+                    "exposure" => {
+                        tone_mapping.exposure = match ar.next().map(|st| st.parse::<f64>()) {
+                            None => {eprintln!("Option --exposure requires a value"); exit(1);},
+                            Some(Ok(v)) => v,
+                            _ => {eprintln!("Invalid exposure value"); exit(1);}
+                        };
+                    },
+                    "tonemap" => {
+                        tone_mapping.operator = match ar.next().map(|st| st.parse::<Operator>()) {
+                            None => {eprintln!("Option --tonemap requires a value"); exit(1);},
+                            Some(Ok(o)) => o,
+                            Some(Err(e)) => {eprintln!("{}", e); exit(1);}
+                        };
+                    },
+                    "no-srgb" => {tone_mapping.srgb = false;},
+                    "fov" => {
+                        fov = match ar.next().map(|st| st.parse::<f64>()) {
+                            None => {eprintln!("Option --fov requires a value"); exit(1);},
+                            Some(Ok(v)) => Some(v),
+                            _ => {eprintln!("Invalid fov value"); exit(1);}
+                        };
+                    },
                     opt @ "width" | opt @ "height" => {
                         let val = match ar.next() {
                             None => {eprintln!("Option --{} requires a value", opt);
@@ -76,7 +154,7 @@ fn main() {
                     i += 1;
                     match flags.next() {
                         None => {break;},
-                        Some('W') => {func = Box::new(raytracer::render_wireframe);},
+                        Some('W') => {func = raytracer::render_wireframe;},
 
                         // Value in this arg or the next?
                         Some(c) if c == 'w' || c == 'h' => {
@@ -109,19 +187,41 @@ fn main() {
         }
     }
 
-    if names.len() > 0 {
+    if !names.is_empty() {
         out_name = names[names.len() - 1].clone();
 
 
         //TODO expand spheres with every input file
         if names.len() > 1 {
-            spheres = match Object::from_file(&names[0]) {
-                Ok(o) => o,
-                Err(s) => {eprintln!("Error reading file: {}", s); exit(1);}
+            scene = match Scene::from_file(&names[0]) {
+                Ok(s) => s,
+                Err(e) => {eprintln!("Error reading scene: {}", e); exit(1);}
             };
         }
     }
-    func(width, height, &spheres, &out_name);
+
+    // Command line options take precedence over the scene file
+    scene.camera = {
+        let c = &scene.camera;
+        match Camera::try_new(eye.unwrap_or(c.eye), target.unwrap_or(c.target),
+                              up.unwrap_or(c.up), fov.unwrap_or(c.fov), c.aspect) {
+            Ok(c) => c,
+            Err(e) => {eprintln!("{}", e); exit(1);}
+        }
+    };
+
+    let mut options = RenderOptions::new(width, height);
+    options.spp = spp;
+    options.filter = filter;
+    options.integrator = integrator;
+    options.tone_mapping = tone_mapping;
+
+    let result = func(&scene, &options)
+        .and_then(|img| img.write(&out_name, &options.tone_mapping));
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
 
     //raytracer::surface_test();
 }
@@ -0,0 +1,300 @@
+use super::vec3::Vec3;
+use super::solids::Object;
+
+const BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 2;
+// Relative cost of visiting a node versus intersecting a primitive
+const TRAVERSAL_COST: f64 = 0.125;
+
+#[derive(Clone, Copy)]
+pub struct Aabb {
+    pub min: Vec3<f64>,
+    pub max: Vec3<f64>
+}
+
+impl Aabb {
+    pub fn new(min: Vec3<f64>, max: Vec3<f64>) -> Self {
+        Aabb {min, max}
+    }
+
+    pub fn empty() -> Self {
+        let inf = f64::INFINITY;
+        Aabb {min: Vec3::new(inf, inf, inf), max: Vec3::new(-inf, -inf, -inf)}
+    }
+
+    pub fn from_points(points: &[Vec3<f64>]) -> Self {
+        points.iter().fold(Aabb::empty(), |b, p| b.grow(*p))
+    }
+
+    pub fn grow(&self, p: Vec3<f64>) -> Self {
+        Aabb {min: self.min.min(&p), max: self.max.max(&p)}
+    }
+
+    pub fn union(&self, other: &Aabb) -> Self {
+        Aabb {min: self.min.min(&other.min), max: self.max.max(&other.max)}
+    }
+
+    pub fn centroid(&self) -> Vec3<f64> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.x < 0. || d.y < 0. || d.z < 0. {
+            return 0.;
+        }
+        2. * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn contains(&self, p: Vec3<f64>, epsilon: f64) -> bool {
+        (0..3).all(|a| p[a] >= self.min[a] - epsilon && p[a] <= self.max[a] + epsilon)
+    }
+
+    pub fn largest_axis(&self) -> usize {
+        let d = self.max - self.min;
+        if d.x > d.y && d.x > d.z {0} else if d.y > d.z {1} else {2}
+    }
+
+    // Slab test, returns the distance at which the ray enters the box
+    pub fn intersect(&self, org: Vec3<f64>, inv_dir: Vec3<f64>, tmax: f64) -> Option<f64> {
+        let mut t0 = 0f64;
+        let mut t1 = tmax;
+        for axis in 0..3 {
+            let ta = (self.min[axis] - org[axis]) * inv_dir[axis];
+            let tb = (self.max[axis] - org[axis]) * inv_dir[axis];
+            t0 = t0.max(ta.min(tb));
+            t1 = t1.min(ta.max(tb));
+        }
+        if t0 <= t1 {Some(t0)} else {None}
+    }
+}
+
+struct Node {
+    bounds: Aabb,
+    // Index of the first child for inner nodes, of the first primitive for leaves
+    start: usize,
+    // Number of primitives, 0 for inner nodes
+    count: usize
+}
+
+pub struct Bvh {
+    nodes: Vec<Node>,
+    pub indices: Vec<usize>
+}
+
+struct Bin {
+    bounds: Aabb,
+    count: usize
+}
+
+impl Bvh {
+    pub fn new(boxes: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(boxes.len() * 2),
+            indices: (0..boxes.len()).collect()
+        };
+        let centroids = boxes.iter().map(|b| b.centroid()).collect::<Vec<_>>();
+        let bounds = boxes.iter().fold(Aabb::empty(), |acc, b| acc.union(b));
+        bvh.nodes.push(Node {bounds, start: 0, count: boxes.len()});
+        if !boxes.is_empty() {
+            bvh.subdivide(0, boxes, &centroids);
+        }
+        bvh
+    }
+
+    pub fn from_objects(objects: &[Object]) -> Self {
+        Bvh::new(&objects.iter().map(|o| o.solid.bounding_box()).collect::<Vec<_>>())
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes[0].bounds
+    }
+
+    fn subdivide(&mut self, node: usize, boxes: &[Aabb], centroids: &[Vec3<f64>]) {
+        let start = self.nodes[node].start;
+        let count = self.nodes[node].count;
+        if count <= MAX_LEAF_SIZE {
+            return;
+        }
+
+        let centroid_bounds = self.indices[start..start + count].iter()
+            .fold(Aabb::empty(), |b, &i| b.grow(centroids[i]));
+        let axis = centroid_bounds.largest_axis();
+        let lo = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - lo;
+        if extent <= 0. {
+            return;
+        }
+
+        // Binned surface area heuristic along the largest axis
+        let bin_of = |c: &Vec3<f64>| {
+            (((c[axis] - lo) / extent * BINS as f64) as usize).min(BINS - 1)
+        };
+        let mut bins = (0..BINS).map(|_| Bin {bounds: Aabb::empty(), count: 0})
+            .collect::<Vec<_>>();
+        for &i in &self.indices[start..start + count] {
+            let b = &mut bins[bin_of(&centroids[i])];
+            b.bounds = b.bounds.union(&boxes[i]);
+            b.count += 1;
+        }
+
+        let mut best_cost = f64::INFINITY;
+        let mut best_split = 0;
+        for split in 1..BINS {
+            let (left, right) = bins.split_at(split);
+            let (lb, lc) = left.iter()
+                .fold((Aabb::empty(), 0), |(b, c), bin| (b.union(&bin.bounds), c + bin.count));
+            let (rb, rc) = right.iter()
+                .fold((Aabb::empty(), 0), |(b, c), bin| (b.union(&bin.bounds), c + bin.count));
+            if lc == 0 || rc == 0 {
+                continue;
+            }
+            let cost = lb.surface_area() * lc as f64 + rb.surface_area() * rc as f64;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        let parent_area = self.nodes[node].bounds.surface_area();
+        let leaf_cost = count as f64;
+        if best_split == 0 ||
+            (parent_area > 0. && TRAVERSAL_COST + best_cost / parent_area >= leaf_cost) {
+            return;
+        }
+
+        // Partition the primitives in place
+        let mut i = start;
+        let mut j = start + count;
+        while i < j {
+            if bin_of(&centroids[self.indices[i]]) < best_split {
+                i += 1;
+            } else {
+                j -= 1;
+                self.indices.swap(i, j);
+            }
+        }
+        let left_count = i - start;
+
+        let child = self.nodes.len();
+        for &(s, c) in &[(start, left_count), (i, count - left_count)] {
+            let bounds = self.indices[s..s + c].iter()
+                .fold(Aabb::empty(), |b, &k| b.union(&boxes[k]));
+            self.nodes.push(Node {bounds, start: s, count: c});
+        }
+        self.nodes[node].start = child;
+        self.nodes[node].count = 0;
+
+        self.subdivide(child, boxes, centroids);
+        self.subdivide(child + 1, boxes, centroids);
+    }
+
+    // Closest primitive hit up to `tmax`. `hit` is given the distance to the
+    // closest hit so far and returns the distance to its own along with any
+    // data to keep about it.
+    pub fn intersect<H, F>(&self, org: Vec3<f64>, dir: Vec3<f64>, tmax: f64, mut hit: F)
+        -> Option<(usize, H)> where F: FnMut(usize, f64) -> Option<(f64, H)> {
+        let inv_dir = Vec3::new(1. / dir.x, 1. / dir.y, 1. / dir.z);
+        let mut closest: Option<(usize, H)> = None;
+        let mut tnear = tmax;
+
+        if self.indices.is_empty() ||
+            self.nodes[0].bounds.intersect(org, inv_dir, tnear).is_none() {
+            return None;
+        }
+        let mut stack = vec![0];
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            if node.count > 0 {
+                for &i in &self.indices[node.start..node.start + node.count] {
+                    if let Some((t, h)) = hit(i, tnear) {
+                        if t <= tnear {
+                            tnear = t;
+                            closest = Some((i, h));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            // Visit the nearest child first
+            let l = self.nodes[node.start].bounds.intersect(org, inv_dir, tnear);
+            let r = self.nodes[node.start + 1].bounds.intersect(org, inv_dir, tnear);
+            match (l, r) {
+                (Some(tl), Some(tr)) => if tl < tr {
+                    stack.push(node.start + 1);
+                    stack.push(node.start);
+                } else {
+                    stack.push(node.start);
+                    stack.push(node.start + 1);
+                },
+                (Some(_), None) => {stack.push(node.start);},
+                (None, Some(_)) => {stack.push(node.start + 1);},
+                (None, None) => ()
+            }
+        }
+
+        closest
+    }
+
+    // Whether any primitive is hit before `tmax`, stopping at the first one
+    // found
+    pub fn intersect_any<F>(&self, org: Vec3<f64>, dir: Vec3<f64>, tmax: f64, mut hit: F) -> bool
+        where F: FnMut(usize) -> bool {
+        let inv_dir = Vec3::new(1. / dir.x, 1. / dir.y, 1. / dir.z);
+        if self.indices.is_empty() {
+            return false;
+        }
+
+        let mut stack = vec![0];
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            if node.bounds.intersect(org, inv_dir, tmax).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                if self.indices[node.start..node.start + node.count].iter().any(|&i| hit(i)) {
+                    return true;
+                }
+            } else {
+                stack.push(node.start);
+                stack.push(node.start + 1);
+            }
+        }
+
+        false
+    }
+}
+
+#[test]
+fn bvh_closest_hit_test() {
+    use super::solids::Solid;
+    use super::solids::sphere::Sphere;
+
+    let spheres = (0..50).map(|i| {
+        let f = i as f64;
+        Sphere::new(Vec3::new((f * 1.7) % 9. - 4., (f * 3.1) % 7. - 3., -5. - f), 0.5 + f % 3.)
+    }).collect::<Vec<_>>();
+    let bvh = Bvh::new(&spheres.iter().map(|s| s.bounding_box()).collect::<Vec<_>>());
+
+    for k in 0..100 {
+        let a = k as f64 * 0.37;
+        let mut dir = Vec3::new(a.sin() * 0.4, a.cos() * 0.3, -1.);
+        dir.normalize();
+        let org = Vec3::default();
+
+        let brute = spheres.iter().enumerate()
+            .filter_map(|(i, s)| s.intersect(org, dir, 0., f64::INFINITY).map(|h| (i, h.t)))
+            .fold(None, |acc: Option<(usize, f64)>, (i, t)| match acc {
+                Some((_, tb)) if tb <= t => acc,
+                _ => Some((i, t))
+            });
+        let fast = bvh.intersect(org, dir, f64::INFINITY, |i, tmax| {
+            spheres[i].intersect(org, dir, 0., tmax).map(|h| (h.t, h.t))
+        });
+        assert_eq!(brute.map(|h| h.0), fast.map(|h| h.0));
+        assert_eq!(brute.is_some(), bvh.intersect_any(org, dir, f64::INFINITY, |i| {
+            spheres[i].intersect(org, dir, 0., f64::INFINITY).is_some()
+        }));
+    }
+}
@@ -0,0 +1,121 @@
+use std::str::FromStr;
+use super::vec3::Vec3;
+use super::error::{Error, ParseError, Result, column_of, fields, field_count, parse_field,
+                   parse_number};
+
+pub struct Camera {
+    pub eye: Vec3<f64>,
+    pub target: Vec3<f64>,
+    pub up: Vec3<f64>,
+    // Vertical field of view, in degrees
+    pub fov: f64,
+    pub aspect: f64,
+
+    // Orthonormal basis; w points backwards, away from the target
+    pub u: Vec3<f64>,
+    pub v: Vec3<f64>,
+    pub w: Vec3<f64>,
+    pub angle: f64
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::new(Vec3::default(), Vec3::new(0., 0., -1.), Vec3::new(0., 1., 0.), 50., 1.)
+    }
+}
+
+impl Camera {
+    pub fn new(eye: Vec3<f64>, target: Vec3<f64>, up: Vec3<f64>, fov: f64, aspect: f64) -> Self {
+        let mut w = eye - target;
+        w.normalize();
+        let mut u = up.cross(w);
+        u.normalize();
+        let v = w.cross(u);
+        let angle = (::std::f64::consts::PI * 0.5 * fov / 180.).tan();
+
+        Camera {eye, target, up, fov, aspect, u, v, w, angle}
+    }
+
+    // Checked version of `new`, rejecting settings that leave the view
+    // direction or the basis undefined
+    pub fn try_new(eye: Vec3<f64>, target: Vec3<f64>, up: Vec3<f64>, fov: f64, aspect: f64)
+        -> Result<Self> {
+        match invalid_field(eye, target, up, fov) {
+            Some((_, message)) => Err(Error::InvalidParameter(message.to_string())),
+            None => Ok(Camera::new(eye, target, up, fov, aspect))
+        }
+    }
+
+    pub fn look_at(&self, eye: Vec3<f64>, target: Vec3<f64>, up: Vec3<f64>) -> Self {
+        Camera::new(eye, target, up, self.fov, self.aspect)
+    }
+
+    pub fn with_fov(&self, fov: f64) -> Self {
+        Camera::new(self.eye, self.target, self.up, fov, self.aspect)
+    }
+
+    pub fn with_aspect(&self, aspect: f64) -> Self {
+        Camera::new(self.eye, self.target, self.up, self.fov, aspect)
+    }
+
+    // Primary ray through a point of the image plane, with (0, 0) being the
+    // top left corner and (1, 1) the bottom right one
+    pub fn ray(&self, sx: f64, sy: f64) -> (Vec3<f64>, Vec3<f64>) {
+        let xx = (2. * sx - 1.) * self.angle * self.aspect;
+        let yy = (1. - 2. * sy) * self.angle;
+        let mut dir = self.u * xx + self.v * yy - self.w;
+        dir.normalize();
+        (self.eye, dir)
+    }
+}
+
+// Index of the first of eye, target, up and fov making a degenerate camera,
+// and why
+fn invalid_field(eye: Vec3<f64>, target: Vec3<f64>, up: Vec3<f64>, fov: f64)
+    -> Option<(usize, &'static str)> {
+    let dir = eye - target;
+    let len2 = dir.len_sqr();
+    if len2 == 0. || !len2.is_finite() {
+        return Some((1, "camera target must differ from the eye"));
+    }
+    let side = up.cross(dir).len_sqr();
+    if side.is_nan() || side <= 1e-12 * up.len_sqr() * len2 {
+        return Some((2, "camera up vector must not be parallel to the view direction"));
+    }
+    if !(fov > 0. && fov < 180.) {
+        return Some((3, "field of view must be between 0 and 180 degrees"));
+    }
+    None
+}
+
+// eye, target, up, fov
+impl FromStr for Camera {
+    type Err = ParseError;
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        let vals = fields(s);
+        if vals.len() != 4 {
+            return Err(field_count(s, 4, "camera"));
+        }
+        let eye = parse_field(s, vals[0])?;
+        let target = parse_field(s, vals[1])?;
+        let up = parse_field(s, vals[2])?;
+        let fov = parse_number(s, vals[3], "field of view")?;
+        if let Some((i, message)) = invalid_field(eye, target, up, fov) {
+            return Err(ParseError::new(message, vals[i]).at_column(column_of(s, vals[i])));
+        }
+        Ok(Camera::new(eye, target, up, fov, 1.))
+    }
+}
+
+#[test]
+fn camera_parse_test() {
+    assert!("0 0 0, 0 0 -1, 0 1 0, 50".parse::<Camera>().is_ok());
+    let column = |s: &str| match s.parse::<Camera>() {
+        Err(e) => e.column,
+        _ => panic!("expected a parse error")
+    };
+    assert_eq!(column("0 0 0, 0 0 0, 0 1 0, 50"), 8);
+    assert_eq!(column("0 0 0, 0 0 -1, 0 0 2, 50"), 16);
+    assert_eq!(column("0 0 0, 0 0 -1, 0 1 0, 180"), 23);
+    assert_eq!(column("0 0 0, 0 0 -1, 0 1 0, 0"), 23);
+}
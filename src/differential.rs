@@ -0,0 +1,110 @@
+use vec3::Vec3;
+use solids::{Hit, Solid};
+
+// Rays offset by one pixel along x and y from a camera ray, followed through
+// specular bounces to estimate its footprint on surfaces (Igehy, "Tracing Ray
+// Differentials")
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RayDifferential {
+    // Origin and direction of each offset ray
+    pub rx: (Vec3<f64>, Vec3<f64>),
+    pub ry: (Vec3<f64>, Vec3<f64>)
+}
+
+// Where the offset rays of a differential meet a surface
+pub struct Footprint {
+    pub dirs: [Vec3<f64>; 2],
+    pub points: [Vec3<f64>; 2],
+    // Shading normals, facing the offset rays
+    pub normals: [Vec3<f64>; 2]
+}
+
+impl RayDifferential {
+    pub fn new(rx: (Vec3<f64>, Vec3<f64>), ry: (Vec3<f64>, Vec3<f64>)) -> Self {
+        RayDifferential {rx, ry}
+    }
+
+    // Offset rays `s` times as far from the main one, e.g. to match the
+    // spacing of several samples per pixel
+    pub fn scale(&self, org: Vec3<f64>, dir: Vec3<f64>, s: f64) -> Self {
+        let f = |(o, d): (Vec3<f64>, Vec3<f64>)| {
+            let mut d = dir + (d - dir) * s;
+            (org + (o - org) * s, *d.normalize())
+        };
+        RayDifferential::new(f(self.rx), f(self.ry))
+    }
+
+    // Offset rays are intersected with the solid that was hit, falling back
+    // to the tangent plane at `hit` when they miss it
+    pub fn footprint(&self, hit: &Hit, solid: &dyn Solid) -> Footprint {
+        let meet = |(o, d): (Vec3<f64>, Vec3<f64>)| {
+            let (p, mut n) = match solid.intersect(o, d, 0., f64::INFINITY) {
+                Some(ref h) if h.front_face == hit.front_face => (h.point, h.shading_normal),
+                _ => {
+                    let denom = hit.normal.dot(&d);
+                    let t = if denom.abs() > 1e-12 {hit.normal.dot(&(hit.point - o)) / denom}
+                            else {0.};
+                    (o + d * t.max(0.), hit.shading_normal)
+                }
+            };
+            if n.dot(&d) > 0. {
+                n = -n;
+            }
+            (p, n)
+        };
+        let (px, nx) = meet(self.rx);
+        let (py, ny) = meet(self.ry);
+        Footprint {dirs: [self.rx.1, self.ry.1], points: [px, py], normals: [nx, ny]}
+    }
+}
+
+impl Footprint {
+    // Derivatives of the texture coordinates along the offset rays, from the
+    // position derivatives `dpdu` and `dpdv` at `hit`
+    pub fn uv_derivatives(&self, hit: &Hit, dpdu: Vec3<f64>, dpdv: Vec3<f64>)
+        -> [(f64, f64); 2] {
+        // Least squares solution of dpdu * du + dpdv * dv = dp
+        let (a, b, c) = (dpdu.dot(&dpdu), dpdu.dot(&dpdv), dpdv.dot(&dpdv));
+        let det = a * c - b * b;
+        let solve = |p: Vec3<f64>| {
+            if det.abs() < 1e-20 {
+                return (0., 0.);
+            }
+            let dp = p - hit.point;
+            let (pu, pv) = (dpdu.dot(&dp), dpdv.dot(&dp));
+            ((c * pu - b * pv) / det, (a * pv - b * pu) / det)
+        };
+        [solve(self.points[0]), solve(self.points[1])]
+    }
+
+    // Offset rays leaving the surface, `bounce` mapping a point, incoming
+    // direction and normal to the new origin and direction as is done for
+    // the main ray
+    pub fn bounce<F>(&self, bounce: F) -> RayDifferential
+        where F: Fn(Vec3<f64>, Vec3<f64>, Vec3<f64>) -> (Vec3<f64>, Vec3<f64>) {
+        RayDifferential::new(bounce(self.points[0], self.dirs[0], self.normals[0]),
+                             bounce(self.points[1], self.dirs[1], self.normals[1]))
+    }
+}
+
+#[test]
+fn differential_test() {
+    use solids::rectangle::Rectangle;
+
+    // Unit square on the z = -2 plane, u along x and v along -y
+    let rect = Rectangle::new(Vec3::new(0., 1., -2.), Vec3::new(0., 0., -2.),
+                              Vec3::new(1., 1., -2.), Vec3::new(1., 0., -2.));
+    let org = Vec3::default();
+    let mut dir = Vec3::new(0.5, 0.5, -2.);
+    dir.normalize();
+    let offset = |x: f64, y: f64| {
+        let mut d = Vec3::new(0.5 + x, 0.5 + y, -2.);
+        (org, *d.normalize())
+    };
+    let diff = RayDifferential::new(offset(0.01, 0.), offset(0., -0.01));
+    let hit = rect.intersect(org, dir, 0., f64::INFINITY).unwrap();
+    let (dpdu, dpdv) = rect.tangents_at(&hit);
+    let duv = diff.footprint(&hit, &rect).uv_derivatives(&hit, dpdu, dpdv);
+    assert!((duv[0].0 - 0.01).abs() < 1e-9 && duv[0].1.abs() < 1e-9);
+    assert!(duv[1].0.abs() < 1e-9 && (duv[1].1 - 0.01).abs() < 1e-9);
+}
@@ -0,0 +1,160 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub file: Option<String>,
+    // 1-based, 0 when unknown
+    pub line: usize,
+    pub column: usize,
+    // Offending token
+    pub token: String,
+    pub message: String
+}
+
+impl ParseError {
+    pub fn new(message: &str, token: &str) -> Self {
+        ParseError {file: None, line: 0, column: 0, token: token.to_string(),
+            message: message.to_string()}
+    }
+
+    // `column` is the 1-based column of the token in its parent string
+    pub fn at_column(mut self, column: usize) -> Self {
+        self.column = column;
+        self
+    }
+
+    // Shifts an error reported on a substring starting at `column`
+    pub fn offset(mut self, column: usize) -> Self {
+        self.column = if self.column == 0 {column} else {self.column + column - 1};
+        self
+    }
+
+    pub fn at(self, line: usize, column: usize) -> Self {
+        let mut e = self.offset(column);
+        e.line = line;
+        e
+    }
+
+    // Names what was being parsed, e.g. "Invalid number in surface color"
+    pub fn context(mut self, what: &str) -> Self {
+        self.message = format!("{} in {}", self.message, what);
+        self
+    }
+
+    pub fn in_file(mut self, file: &str) -> Self {
+        self.file = Some(file.to_string());
+        self
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file)?;
+        }
+        if self.line > 0 {
+            write!(f, "{}:{}: ", self.line, self.column)?;
+        }
+        write!(f, "{}", self.message)?;
+        if !self.token.is_empty() {
+            write!(f, " `{}`", self.token)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for ParseError {}
+
+// 1-based column of `token` inside `s`, `token` being a subslice of `s`
+pub fn column_of(s: &str, token: &str) -> usize {
+    let start = s.as_ptr() as usize;
+    let pos = token.as_ptr() as usize;
+    if pos >= start && pos <= start + s.len() {pos - start + 1} else {0}
+}
+
+// Parses `token`, a subslice of `s`, reporting its position on failure
+pub fn parse_number<T: FromStr>(s: &str, token: &str, what: &str)
+    -> ::std::result::Result<T, ParseError> {
+    T::from_str(token.trim()).map_err(|_| {
+        ParseError::new(&format!("Invalid {}", what), token.trim())
+            .at_column(column_of(s, token.trim()))
+    })
+}
+
+// Parses `field`, a subslice of `s`, with errors located relative to `s`
+pub fn parse_field<T>(s: &str, field: &str) -> ::std::result::Result<T, ParseError>
+    where T: FromStr<Err = ParseError> {
+    T::from_str(field).map_err(|e| e.offset(column_of(s, field)))
+}
+
+pub fn field_count(s: &str, expected: usize, what: &str) -> ParseError {
+    ParseError::new(&format!("Expected {} fields in {} definition", expected, what), s.trim())
+        .at_column(column_of(s, s.trim()))
+}
+
+// Comma separated fields of `s`, trimmed
+pub fn fields(s: &str) -> Vec<&str> {
+    s.split(',').map(|f| f.trim()).collect()
+}
+
+// What follows the `from`th field of `s`, `fields` coming from `fields(s)`
+pub fn rest<'a>(s: &'a str, fields: &[&'a str], from: usize) -> &'a str {
+    match fields.get(from) {
+        Some(f) => &s[column_of(s, f) - 1..],
+        None => ""
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io {path: Option<String>, source: io::Error},
+    Parse(ParseError),
+    // Image encoder failures
+    Encoding(String),
+    InvalidParameter(String)
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl Error {
+    pub fn io(path: &str, source: io::Error) -> Self {
+        Error::Io {path: Some(path.to_string()), source}
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io {path: Some(ref p), ref source} => write!(f, "{}: {}", p, source),
+            Error::Io {path: None, ref source} => write!(f, "{}", source),
+            Error::Parse(ref e) => write!(f, "{}", e),
+            Error::Encoding(ref s) => write!(f, "Encoding error: {}", s),
+            Error::InvalidParameter(ref s) => write!(f, "Invalid parameter: {}", s)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io {ref source, ..} => Some(source),
+            Error::Parse(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io {path: None, source}
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
@@ -0,0 +1,151 @@
+use std::str::FromStr;
+use super::error::ParseError;
+use super::vec3::Vec3;
+use super::rand::{Rng, SeedableRng, XorShiftRng};
+
+// Fraction of the weight of a centred sample, per sample, below which the
+// total weight of a pixel is too small to divide by
+const MIN_WEIGHT: f64 = 0.05;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell
+}
+
+impl Filter {
+    // Half-width of the filter's support, in pixels
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.
+        }
+    }
+
+    // Filters are separable
+    pub fn eval(&self, x: f64, y: f64) -> f64 {
+        self.eval_1d(x) * self.eval_1d(y)
+    }
+
+    // Weighted mean of the values of samples at the given offsets from the
+    // pixel centre. With few samples, negative lobes can leave a total weight
+    // near zero or below: negative weights are dropped then, and the plain
+    // mean is used if nothing is left.
+    pub fn resolve(&self, samples: &[((f64, f64), Vec3<f64>)]) -> Vec3<f64> {
+        let weighted = |clamp: bool| {
+            let mut color = Vec3::default();
+            let mut weight = 0.;
+            for &((dx, dy), v) in samples {
+                let w = if clamp {self.eval(dx, dy).max(0.)} else {self.eval(dx, dy)};
+                color = color + v * w;
+                weight += w;
+            }
+            (color, weight)
+        };
+        let (color, weight) = weighted(false);
+        if weight > MIN_WEIGHT * self.eval(0., 0.) * samples.len() as f64 {
+            return color * (1. / weight);
+        }
+        let (color, weight) = weighted(true);
+        if weight > 0. {
+            return color * (1. / weight);
+        }
+        if samples.is_empty() {
+            return Vec3::default();
+        }
+        samples.iter().fold(Vec3::default(), |c, &(_, v)| c + v) * (1. / samples.len() as f64)
+    }
+
+    fn eval_1d(&self, x: f64) -> f64 {
+        let r = self.radius();
+        let x = x.abs();
+        if x > r {
+            return 0.;
+        }
+        match *self {
+            Filter::Box => 1.,
+            Filter::Tent => 1. - x / r,
+            Filter::Gaussian => {
+                let alpha = 2.;
+                (-alpha * x * x).exp() - (-alpha * r * r).exp()
+            },
+            Filter::Mitchell => {
+                let (b, c) = (1. / 3., 1. / 3.);
+                if x < 1. {
+                    ((12. - 9. * b - 6. * c) * x * x * x + (-18. + 12. * b + 6. * c) * x * x +
+                     (6. - 2. * b)) / 6.
+                } else {
+                    ((-b - 6. * c) * x * x * x + (6. * b + 30. * c) * x * x +
+                     (-12. * b - 48. * c) * x + (8. * b + 24. * c)) / 6.
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "tent" => Ok(Filter::Tent),
+            "gaussian" => Ok(Filter::Gaussian),
+            "mitchell" => Ok(Filter::Mitchell),
+            _ => Err(ParseError::new("Unknown filter, expected one of box, tent, gaussian, \
+                                      mitchell", s))
+        }
+    }
+}
+
+// Per pixel generator, seeded from the pixel coordinates so renders are
+// reproducible regardless of thread scheduling
+pub fn pixel_rng(x: usize, y: usize) -> XorShiftRng {
+    XorShiftRng::from_seed([x as u32 + 1, y as u32 + 1, 0x9e37_79b9, 0x7f4a_7c15])
+}
+
+// Jittered stratified samples in [0, 1)^2. The largest square grid fitting in
+// `count` is stratified, remaining samples are uniformly random.
+pub fn stratified_samples<R: Rng>(rng: &mut R, count: usize) -> Vec<(f64, f64)> {
+    let n = (count as f64).sqrt() as usize;
+    let inv_n = 1. / n as f64;
+    let mut samples = Vec::with_capacity(count);
+    for j in 0..n {
+        for i in 0..n {
+            samples.push(((i as f64 + rng.gen::<f64>()) * inv_n,
+                          (j as f64 + rng.gen::<f64>()) * inv_n));
+        }
+    }
+    while samples.len() < count {
+        samples.push((rng.gen(), rng.gen()));
+    }
+    samples
+}
+
+// Stratified offsets in [-radius, radius]^2, a single sample being centred
+pub fn stratified_offsets<R: Rng>(rng: &mut R, count: usize, radius: f64) -> Vec<(f64, f64)> {
+    if count == 1 {
+        return vec![(0., 0.)];
+    }
+    stratified_samples(rng, count).into_iter()
+        .map(|(sx, sy)| ((2. * sx - 1.) * radius, (2. * sy - 1.) * radius))
+        .collect()
+}
+
+#[test]
+fn mitchell_low_spp_test() {
+    let mitchell = Filter::Mitchell;
+    // Every sample in the negative lobe
+    let samples: Vec<_> = [(1.5, 0.), (-1.5, 0.), (0., 1.5), (0., -1.5)].iter()
+        .zip(&[1., 2., 3., 4.]).map(|(&o, &v)| (o, Vec3::new(v, v, v))).collect();
+    assert_eq!(mitchell.resolve(&samples).x, 2.5);
+
+    // A centred sample outweighed by the negative lobe: the weight left is
+    // positive but tiny, only the centred sample counts
+    let mut samples = vec![((0., 0.), Vec3::new(2., 2., 2.))];
+    samples.extend((0..20).map(|_| ((1.5, 0.), Vec3::new(100., 100., 100.))));
+    assert_eq!(mitchell.resolve(&samples).x, 2.);
+}
@@ -0,0 +1,75 @@
+use super::vec3::Vec3;
+use super::output::{self, write_to_file};
+use super::tonemap::ToneMapping;
+use super::error::{Error, Result};
+
+// Linear RGB radiance, row major with (0, 0) being the top left pixel
+#[derive(Clone)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec3<f64>>
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Result<Self> {
+        Framebuffer::from_pixels(width, height, vec![Vec3::default(); width * height])
+    }
+
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<Vec3<f64>>) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidParameter(
+                format!("image size must be positive, got {}x{}", width, height)));
+        }
+        if pixels.len() != width * height {
+            return Err(Error::InvalidParameter(
+                format!("{} pixels do not make a {}x{} image", pixels.len(), width, height)));
+        }
+        Ok(Framebuffer {width, height, pixels})
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Vec3<f64> {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, c: Vec3<f64>) {
+        self.pixels[y * self.width + x] = c;
+    }
+
+    pub fn rows(&self) -> ::std::slice::Chunks<'_, Vec3<f64>> {
+        self.pixels.chunks(self.width)
+    }
+
+    pub fn rows_mut(&mut self) -> ::std::slice::ChunksMut<'_, Vec3<f64>> {
+        self.pixels.chunks_mut(self.width)
+    }
+
+    // Tone mapped 8-bit RGB triplets, for display or custom encoding
+    pub fn to_rgb8(&self, tone_mapping: &ToneMapping) -> Vec<u8> {
+        output::to_rgb8(&self.pixels, tone_mapping)
+    }
+
+    // The format is chosen from the extension, "-" writes a PNG to stdout
+    pub fn write(&self, filename: &str, tone_mapping: &ToneMapping) -> Result<()> {
+        write_to_file(self.width, self.height, &self.pixels, filename, tone_mapping)
+    }
+}
+
+#[test]
+fn framebuffer_test() {
+    let mut fb = Framebuffer::new(3, 2).unwrap();
+    assert_eq!((fb.width, fb.height, fb.pixels.len()), (3, 2, 6));
+    fb.set(2, 1, Vec3::new(1., 2., 3.));
+    let xyz = |c: Vec3<f64>| (c.x, c.y, c.z);
+    assert_eq!(xyz(fb.get(2, 1)), (1., 2., 3.));
+    // Row major, the last pixel of the last row
+    assert_eq!(xyz(fb.pixels[5]), (1., 2., 3.));
+    assert_eq!(xyz(fb.rows().nth(1).unwrap()[2]), (1., 2., 3.));
+    assert_eq!(fb.rows().count(), 2);
+
+    let pixels: Vec<_> = (0..6).map(|i| Vec3::new(i as f64, 0., 0.)).collect();
+    let fb = Framebuffer::from_pixels(2, 3, pixels).unwrap();
+    assert_eq!(fb.get(1, 2).x, 5.);
+    assert!(Framebuffer::from_pixels(2, 2, vec![Vec3::default(); 3]).is_err());
+    assert!(Framebuffer::new(0, 1).is_err());
+}
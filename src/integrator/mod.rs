@@ -0,0 +1,273 @@
+pub mod whitted;
+pub mod path;
+
+use std::f64::consts::PI;
+use rand::{Rng, XorShiftRng};
+use vec3::{Vec3, basis, reflect, refract};
+use solids::{Object, Hit, closest_hit};
+use scene::Scene;
+use bvh::Bvh;
+use differential::{RayDifferential, Footprint};
+use light::{Light, LightSample};
+use material::Material;
+
+// Computes the radiance arriving at `org` from direction `-dir`. `bvh` is
+// built over the scene objects, and `rng` is seeded per pixel.
+pub trait Integrator {
+    fn radiance(&self, org: Vec3<f64>, dir: Vec3<f64>, scene: &Scene, bvh: &Bvh,
+                rng: &mut XorShiftRng) -> Vec3<f64>;
+
+    // Same for a camera ray with its differential, which integrators that
+    // filter textures follow through specular bounces
+    fn radiance_differential(&self, org: Vec3<f64>, dir: Vec3<f64>, _diff: &RayDifferential,
+                             scene: &Scene, bvh: &Bvh, rng: &mut XorShiftRng) -> Vec3<f64> {
+        self.radiance(org, dir, scene, bvh, rng)
+    }
+}
+
+pub fn by_name(name: &str) -> Option<Box<dyn Integrator + Sync>> {
+    match name {
+        "whitted" => Some(Box::new(whitted::Whitted)),
+        "path" => Some(Box::new(path::Path)),
+        "ao" => Some(Box::new(AmbientOcclusion::new(16, f64::INFINITY))),
+        "normals" => Some(Box::new(Normals)),
+        "depth" => Some(Box::new(Depth)),
+        _ => None
+    }
+}
+
+pub fn is_emissive(o: &Object) -> bool {
+    let e = o.emission_color;
+    e.x > 0. || e.y > 0. || e.z > 0.
+}
+
+// Area lights of the objects sampled for next event estimation
+pub fn emitters(objects: &[Object]) -> Vec<Light> {
+    objects.iter().enumerate().filter(|&(_, o)| is_emissive(o) && o.solid.area() > 0.)
+        .map(|(i, _)| Light::Area {object: i}).collect()
+}
+
+// Whether nothing blocks the way from `org` to a light sample, transparent
+// objects included
+pub fn unoccluded(org: Vec3<f64>, sample: &LightSample, scene: &Scene, bvh: &Bvh) -> bool {
+    let t_max = sample.dist * (1. - 1e-6) - scene.settings.bias;
+    let objects = &scene.objects;
+    !bvh.intersect_any(org, sample.wi, t_max, |j| {
+        Some(j) != sample.object && objects[j].solid.intersect(org, sample.wi, 0., t_max).is_some()
+    })
+}
+
+// Whether light passes through `o`, filtered by `shadow_filter`
+fn is_transparent(o: &Object) -> bool {
+    match o.material {
+        Some(Material::Dielectric {..}) => true,
+        Some(_) => false,
+        None => o.transparency > 0.
+    }
+}
+
+fn shadow_filter(o: &Object, hit: &Hit) -> Vec3<f64> {
+    match o.material {
+        Some(_) => o.color_at(hit),
+        None => o.color_at(hit) * o.transparency
+    }
+}
+
+// Offset rays of `footprint` following a specular lobe to `wi`, reflected or
+// refracted with the ratio of indices `eta` depending on the side of `n` it
+// leaves on
+pub fn specular_bounce(footprint: &Footprint, wi: Vec3<f64>, n: Vec3<f64>, eta: f64, bias: f64)
+    -> RayDifferential {
+    if wi.dot(&n) > 0. {
+        footprint.bounce(|p, d, n| (p + n * bias, reflect(d, n)))
+    } else {
+        footprint.bounce(|p, d, n| match refract(d, n, eta) {
+            Some(r) => (p - n * bias, r),
+            None => (p + n * bias, reflect(d, n))
+        })
+    }
+}
+
+// Surfaces crossed by a shadow ray before it is given up as fully blocked
+const MAX_SHADOW_CROSSINGS: usize = 32;
+
+// Fraction of the light of a sample reaching `org`, per channel. Opaque
+// objects block it, transparent ones filter it by their colour and
+// transparency at every surface crossed. Rays stop short of the sample so the
+// light's own surface doesn't count, and nothing behind it does either.
+pub fn transmittance(org: Vec3<f64>, sample: &LightSample, scene: &Scene, bvh: &Bvh)
+    -> Vec3<f64> {
+    let t_max = sample.dist * (1. - 1e-6) - scene.settings.bias;
+    let objects = &scene.objects;
+    let dir = sample.wi;
+    let blocks = |j: usize| Some(j) != sample.object && !is_transparent(&objects[j]);
+    if bvh.intersect_any(org, dir, t_max, |j| {
+        blocks(j) && objects[j].solid.intersect(org, dir, 0., t_max).is_some()
+    }) {
+        return Vec3::default();
+    }
+
+    // Only transparent surfaces remain, walked through in order
+    let mut filter = Vec3::new(1., 1., 1.);
+    let mut t_min = 0.;
+    for _ in 0..MAX_SHADOW_CROSSINGS {
+        let (i, hit) = match bvh.intersect(org, dir, t_max, |j, t| {
+            if Some(j) == sample.object || !is_transparent(&objects[j]) {
+                return None;
+            }
+            objects[j].solid.intersect(org, dir, t_min, t).map(|h| (h.t, h))
+        }) {
+            Some(h) => h,
+            None => {return filter;}
+        };
+        filter = filter * shadow_filter(&objects[i], &hit);
+        if filter.x <= 0. && filter.y <= 0. && filter.z <= 0. {
+            break;
+        }
+        t_min = hit.t + scene.settings.bias;
+    }
+    Vec3::default()
+}
+
+pub fn cosine_hemisphere(n: Vec3<f64>, u1: f64, u2: f64) -> Vec3<f64> {
+    let r = u1.sqrt();
+    let phi = 2. * PI * u2;
+    let (t, b) = basis(n);
+    t * (r * phi.cos()) + b * (r * phi.sin()) + n * (1. - u1).max(0.).sqrt()
+}
+
+// Fraction of the hemisphere around the hit left unoccluded within `distance`
+pub struct AmbientOcclusion {
+    pub samples: usize,
+    pub distance: f64
+}
+
+impl AmbientOcclusion {
+    pub fn new(samples: usize, distance: f64) -> Self {
+        AmbientOcclusion {samples, distance}
+    }
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(&self, org: Vec3<f64>, dir: Vec3<f64>, scene: &Scene, bvh: &Bvh,
+                rng: &mut XorShiftRng) -> Vec3<f64> {
+        let objects = &scene.objects;
+        let (obj, hit) = match closest_hit(objects, bvh, org, dir, 0., f64::INFINITY) {
+            Some((i, h)) => (&objects[i], h),
+            None => {return Vec3::default();}
+        };
+        let n = obj.shading_normal(&hit, &obj.texcoords(&hit, None));
+        let p = hit.point + n * scene.settings.bias;
+
+        let visible = (0..self.samples).filter(|_| {
+            let d = cosine_hemisphere(n, rng.gen(), rng.gen());
+            closest_hit(objects, bvh, p, d, 0., self.distance).is_none()
+        }).count();
+        let v = visible as f64 / self.samples.max(1) as f64;
+        Vec3::new(v, v, v)
+    }
+}
+
+// Shading normals, bump mapped, remapped to [0, 1]
+pub struct Normals;
+
+impl Integrator for Normals {
+    fn radiance(&self, org: Vec3<f64>, dir: Vec3<f64>, scene: &Scene, bvh: &Bvh,
+                _rng: &mut XorShiftRng) -> Vec3<f64> {
+        match closest_hit(&scene.objects, bvh, org, dir, 0., f64::INFINITY) {
+            Some((i, h)) => {
+                let obj = &scene.objects[i];
+                let n = obj.shading_normal(&h, &obj.texcoords(&h, None));
+                (n + Vec3::new(1., 1., 1.)) * 0.5
+            },
+            None => Vec3::default()
+        }
+    }
+}
+
+// Raw distance to the first hit, 0 for rays escaping the scene
+pub struct Depth;
+
+impl Integrator for Depth {
+    fn radiance(&self, org: Vec3<f64>, dir: Vec3<f64>, scene: &Scene, bvh: &Bvh,
+                _rng: &mut XorShiftRng) -> Vec3<f64> {
+        match closest_hit(&scene.objects, bvh, org, dir, 0., f64::INFINITY) {
+            Some((_, h)) => Vec3::new(h.t, h.t, h.t),
+            None => Vec3::default()
+        }
+    }
+}
+
+#[test]
+fn transmittance_test() {
+    use solids::sphere::Sphere;
+
+    let glass = |z: f64, transparency: f64| Object::new(Vec3::new(1., 0.5, 0.), Vec3::default(),
+        0., transparency, Box::new(Sphere::new(Vec3::new(0., 0., z), 1.)));
+    let mut scene = Scene::new(vec![glass(-3., 0.5), glass(-8., 0.)], Default::default());
+    let bvh = Bvh::from_objects(&scene.objects);
+    let sample = |dist: f64| LightSample {wi: Vec3::new(0., 0., -1.), dist,
+        irradiance: Vec3::new(1., 1., 1.), object: None};
+
+    // Filtered when entering and leaving the glass, the opaque sphere behind
+    // the light doesn't count
+    let org = Vec3::default();
+    assert_eq!(transmittance(org, &sample(6.), &scene, &bvh), Vec3::new(0.25, 0.0625, 0.));
+    assert!(unoccluded(org, &sample(1.), &scene, &bvh));
+    assert!(!unoccluded(org, &sample(6.), &scene, &bvh));
+    assert_eq!(transmittance(org, &sample(10.), &scene, &bvh), Vec3::default());
+
+    scene.objects[0].transparency = 0.;
+    assert_eq!(transmittance(org, &sample(6.), &scene, &bvh), Vec3::default());
+}
+
+#[test]
+fn furnace_test() {
+    use rand::SeedableRng;
+    use scene::Background;
+    use solids::sphere::Sphere;
+
+    // A diffuse sphere is convex, its reflected rays all escape to the
+    // background
+    let albedo = 0.5;
+    let sphere = Object::new(Vec3::new(albedo, albedo, albedo), Vec3::default(), 0., 0.,
+                             Box::new(Sphere::new(Vec3::new(0., 0., -5.), 1.)));
+    let mut scene = Scene::new(vec![sphere], Default::default());
+    let bvh = Bvh::from_objects(&scene.objects);
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    let (org, dir) = (Vec3::default(), Vec3::new(0., 0., -1.));
+
+    // Under uniform light every path carries exactly the albedo
+    scene.background = Background::Constant(Vec3::new(1., 1., 1.));
+    for _ in 0..16 {
+        assert!((path::Path.radiance(org, dir, &scene, &bvh, &mut rng).x - albedo).abs() < 1e-9);
+    }
+
+    // Light falling off as cos^2 from the normal is reflected as albedo / 2
+    scene.background = Background::Gradient {color: Vec3::new(1., 1., 1.),
+                                             direction: Vec3::new(0., 0., 1.)};
+    let mut error = |spp: usize| {
+        let sum = (0..spp).fold(0., |s, _| s + path::Path.radiance(org, dir, &scene, &bvh,
+                                                                   &mut rng).x);
+        (sum / spp as f64 - albedo * 0.5).abs()
+    };
+    let (coarse, fine) = (error(16), error(4096));
+    assert!(fine < coarse && fine < 0.01);
+}
+
+#[test]
+fn normals_depth_test() {
+    use solids::sphere::Sphere;
+
+    let sphere = Object::new(Vec3::new(1., 1., 1.), Vec3::default(), 0., 0.,
+                             Box::new(Sphere::new(Vec3::new(0., 0., -5.), 1.)));
+    let scene = Scene::new(vec![sphere], Default::default());
+    let render = |integrator: Box<dyn Integrator + Sync>| {
+        let mut options = ::RenderOptions::new(3, 3);
+        options.integrator = integrator;
+        ::render(&scene, &options).unwrap().get(1, 1)
+    };
+    // Facing the camera, 4 units away
+    assert_eq!(render(Box::new(Normals)), Vec3::new(0.5, 0.5, 1.));
+    assert_eq!(render(Box::new(Depth)), Vec3::new(4., 4., 4.));
+}
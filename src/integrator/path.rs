@@ -0,0 +1,172 @@
+use std::f64::consts::PI;
+use rand::{Rng, XorShiftRng};
+use vec3::{Vec3, reflect, refract};
+use scene::Scene;
+use solids::closest_hit;
+use bvh::Bvh;
+use differential::RayDifferential;
+use light::Light;
+use super::{Integrator, is_emissive, cosine_hemisphere, transmittance, unoccluded,
+            specular_bounce};
+
+// Bounces before paths may be terminated by russian roulette
+const MIN_BOUNCES: usize = 3;
+
+// Direct lighting at `p` from one light picked uniformly, reflected by the
+// BSDF `f` of the direction towards the light
+fn sample_light<R: Rng, F>(p: Vec3<f64>, n: Vec3<f64>, current: usize, scene: &Scene, bvh: &Bvh,
+                           rng: &mut R, f: F) -> Vec3<f64> where F: Fn(Vec3<f64>) -> Vec3<f64> {
+    let lights = &scene.lights;
+    if lights.is_empty() {
+        return Vec3::default();
+    }
+    let light = &lights[rng.gen_range(0, lights.len())];
+    if *light == (Light::Area {object: current}) {
+        return Vec3::default();
+    }
+    let sample = match light.sample(p, &scene.objects, rng.gen(), rng.gen()) {
+        Some(s) => s,
+        None => {return Vec3::default();}
+    };
+
+    let cos_s = n.dot(&sample.wi);
+    if cos_s <= 0. {
+        return Vec3::default();
+    }
+    // Light reaching area lights through glass is already carried by the
+    // specular paths hitting them, delta lights can't be hit though
+    let org = p + n * scene.settings.bias;
+    let filter = match *light {
+        Light::Area {..} => {
+            if !unoccluded(org, &sample, scene, bvh) {
+                return Vec3::default();
+            }
+            Vec3::new(1., 1., 1.)
+        },
+        _ => transmittance(org, &sample, scene, bvh)
+    };
+
+    // Times the number of lights we chose from
+    sample.irradiance * filter * f(sample.wi) * (cos_s * lights.len() as f64)
+}
+
+// `diff` filters textures until the first diffuse bounce, past which
+// footprints are wider than a pixel anyway
+pub fn trace_path<R: Rng>(org: Vec3<f64>, dir: Vec3<f64>, diff: Option<RayDifferential>,
+                          scene: &Scene, bvh: &Bvh, rng: &mut R) -> Vec3<f64> {
+    let objects = &scene.objects;
+    let settings = &scene.settings;
+    let mut radiance = Vec3::default();
+    let mut throughput = Vec3::new(1., 1., 1.);
+    let mut org = org;
+    let mut dir = dir;
+    let mut diff = diff;
+    // Emission is only accounted for on hits not covered by light sampling
+    let mut specular = true;
+
+    for bounce in 0..settings.max_bounces {
+        let (i, hit) = match closest_hit(objects, bvh, org, dir, 0., f64::INFINITY) {
+            Some(h) => h,
+            None => {
+                radiance = radiance + throughput * scene.background.eval(dir);
+                break;
+            }
+        };
+        let obj = &objects[i];
+        let phit = hit.point;
+        let footprint = diff.map(|d| d.footprint(&hit, &*obj.solid));
+        let tc = obj.texcoords(&hit, footprint.as_ref());
+        let n = obj.shading_normal(&hit, &tc);
+        let color = obj.surface.eval(&tc);
+        let bias = settings.bias;
+        let inside = !hit.front_face;
+
+        if specular || !is_emissive(obj) || obj.solid.area() <= 0. {
+            radiance = radiance + throughput * obj.emission_color;
+        }
+
+        let lobe = rng.gen::<f64>();
+        if let Some(ref material) = obj.material {
+            let bsdf = material.bsdf(n, color, inside, settings.fresnel);
+            let wo = -dir;
+            if !bsdf.is_delta() {
+                radiance = radiance + throughput *
+                    sample_light(phit, n, i, scene, bvh, rng, |wi| bsdf.eval(wo, wi));
+            }
+            let s = match bsdf.sample(wo, rng.gen(), rng.gen(), lobe) {
+                Some(s) => s,
+                None => {break;}
+            };
+            let out = s.wi.dot(&n) > 0.;
+            org = if out {phit + n * bias} else {phit - n * bias};
+            diff = if s.delta {footprint.map(|f| specular_bounce(&f, s.wi, n, bsdf.eta, bias))}
+                   else {None};
+            dir = s.wi;
+            throughput = throughput * s.weight;
+            // Light sampling only covers the hemisphere above the surface
+            specular = s.delta || !out;
+        } else if lobe < obj.transparency {
+            let ior = obj.ior.unwrap_or(settings.ior);
+            let eta = if inside {ior} else {1. / ior};
+            let cosi = -n.dot(&dir);
+            // Reflected on total internal reflection
+            match refract(dir, n, eta) {
+                Some(r) if rng.gen::<f64>() >= settings.fresnel.eval(cosi, eta) => {
+                    dir = r;
+                    org = phit - n * bias;
+                    diff = footprint.map(|f| f.bounce(|p, d, n| match refract(d, n, eta) {
+                        Some(r) => (p - n * bias, r),
+                        None => (p + n * bias, reflect(d, n))
+                    }));
+                },
+                _ => {
+                    dir = reflect(dir, n);
+                    org = phit + n * bias;
+                    diff = footprint.map(|f| f.bounce(|p, d, n| (p + n * bias, reflect(d, n))));
+                }
+            }
+            throughput = throughput * color;
+            specular = true;
+        } else if lobe < obj.transparency + (1. - obj.transparency) * obj.reflection {
+            dir = reflect(dir, n);
+            org = phit + n * bias;
+            diff = footprint.map(|f| f.bounce(|p, d, n| (p + n * bias, reflect(d, n))));
+            throughput = throughput * color;
+            specular = true;
+        } else {
+            radiance = radiance + throughput *
+                sample_light(phit, n, i, scene, bvh, rng, |_| color * (1. / PI));
+
+            // The cosine and pdf cancel out with the lambertian BRDF
+            dir = cosine_hemisphere(n, rng.gen(), rng.gen());
+            org = phit + n * bias;
+            diff = None;
+            throughput = throughput * color;
+            specular = false;
+        }
+
+        if bounce >= MIN_BOUNCES {
+            let p = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+            if rng.gen::<f64>() >= p {
+                break;
+            }
+            throughput = throughput * (1. / p);
+        }
+    }
+
+    radiance
+}
+
+pub struct Path;
+
+impl Integrator for Path {
+    fn radiance(&self, org: Vec3<f64>, dir: Vec3<f64>, scene: &Scene, bvh: &Bvh,
+                rng: &mut XorShiftRng) -> Vec3<f64> {
+        trace_path(org, dir, None, scene, bvh, rng)
+    }
+
+    fn radiance_differential(&self, org: Vec3<f64>, dir: Vec3<f64>, diff: &RayDifferential,
+                             scene: &Scene, bvh: &Bvh, rng: &mut XorShiftRng) -> Vec3<f64> {
+        trace_path(org, dir, Some(*diff), scene, bvh, rng)
+    }
+}
@@ -0,0 +1,133 @@
+use std::f64::consts::PI;
+use rand::{Rng, XorShiftRng};
+use vec3::{Vec3, reflect, refract};
+use scene::Scene;
+use solids::closest_hit;
+use bvh::Bvh;
+use differential::RayDifferential;
+use light::Light;
+use filter::stratified_samples;
+use super::{Integrator, transmittance, specular_bounce};
+
+fn mix(a: f64, b: f64, mix: f64) -> f64 {b * mix + a * (1. - mix)}
+
+// Light from the scene lights reflected at `p` by the BSDF `f` of the
+// direction towards the light
+fn direct<R: Rng, F>(p: Vec3<f64>, n: Vec3<f64>, scene: &Scene, bvh: &Bvh, rng: &mut R, f: F)
+    -> Vec3<f64> where F: Fn(Vec3<f64>) -> Vec3<f64> {
+    let mut radiance = Vec3::default();
+    for light in &scene.lights {
+        // Area lights are averaged over stratified points for penumbrae,
+        // one sample is exact for the others
+        let count = match *light {
+            Light::Area {..} => scene.settings.shadow_samples,
+            _ => 1
+        };
+        let mut sum = Vec3::default();
+        for (u, v) in stratified_samples(rng, count) {
+            let sample = match light.sample(p, &scene.objects, u, v) {
+                Some(s) => s,
+                None => {continue;}
+            };
+            let cos = n.dot(&sample.wi);
+            if cos <= 0. {
+                continue;
+            }
+
+            let filter = transmittance(p + n * scene.settings.bias, &sample, scene, bvh);
+            sum = sum + sample.irradiance * filter * f(sample.wi) * cos;
+        }
+        radiance = radiance + sum * (1. / count as f64);
+    }
+    radiance
+}
+
+// `diff` follows the ray's footprint for texture filtering, `rng` samples
+// area lights
+pub fn trace<R: Rng>(org: Vec3<f64>, dir: Vec3<f64>, diff: Option<RayDifferential>,
+                     scene: &Scene, bvh: &Bvh, depth: usize, rng: &mut R) -> Vec3<f64> {
+    let objects = &scene.objects;
+    let settings = &scene.settings;
+    let (obj, hit) = match closest_hit(objects, bvh, org, dir, 0., f64::INFINITY) {
+        None => {return scene.background.eval(dir);},
+        Some((i, h)) => (&objects[i], h)
+    };
+
+    let mut surface_color: Vec3<f64> = Vec3::default();
+    let phit = hit.point;
+    let footprint = diff.map(|d| d.footprint(&hit, &*obj.solid));
+    let tc = obj.texcoords(&hit, footprint.as_ref());
+    let nhit = obj.shading_normal(&hit, &tc);
+    let color = obj.surface.eval(&tc);
+
+    let bias = settings.bias;
+    let inside = !hit.front_face;
+
+    if let Some(ref material) = obj.material {
+        // Specular lobes are followed, the others only gather direct light
+        let bsdf = material.bsdf(nhit, color, inside, settings.fresnel);
+        let wo = -dir;
+        if depth < settings.max_depth {
+            for lobe in bsdf.specular(wo) {
+                let org = if lobe.wi.dot(&nhit) > 0. {phit + nhit * bias} else {phit - nhit * bias};
+                let lobe_diff = footprint.as_ref()
+                    .map(|f| specular_bounce(f, lobe.wi, nhit, bsdf.eta, bias));
+                surface_color = surface_color +
+                    lobe.weight * trace(org, lobe.wi, lobe_diff, scene, bvh, depth + 1, rng);
+            }
+        }
+        if !bsdf.is_delta() {
+            surface_color = surface_color +
+                direct(phit, nhit, scene, bvh, rng, |wi| bsdf.eval(wo, wi));
+        }
+    } else if (obj.transparency > 0. || obj.reflection > 0.) && depth < settings.max_depth {
+        let facingratio = -dir.dot(&nhit);
+        // Opaque objects have no index of refraction to go by
+        let mut fresneleffect = mix((1. - facingratio).powi(3), 1., 0.1);
+
+        let refl_diff = footprint.as_ref()
+            .map(|f| f.bounce(|p, d, n| (p + n * bias, reflect(d, n))));
+        let reflection = trace(phit + nhit * bias, reflect(dir, nhit), refl_diff, scene, bvh,
+                               depth + 1, rng);
+
+        let mut refraction = Vec3::<f64>::default();
+        if obj.transparency > 0. {
+            let ior = obj.ior.unwrap_or(settings.ior);
+            let eta = if inside {ior} else {1. / ior};
+            fresneleffect = settings.fresnel.eval(facingratio, eta);
+
+            // Everything is reflected past the critical angle
+            if let Some(refrdir) = refract(dir, nhit, eta) {
+                let refr_diff = footprint.as_ref().map(|f| f.bounce(|p, d, n| {
+                    match refract(d, n, eta) {
+                        Some(r) => (p - n * bias, r),
+                        None => (p + n * bias, reflect(d, n))
+                    }
+                }));
+                refraction = trace(phit - nhit * bias, refrdir, refr_diff, scene, bvh,
+                                   depth + 1, rng);
+            }
+        }
+        surface_color = color * (reflection * fresneleffect +
+                            refraction *(1. - fresneleffect) * obj.transparency);
+    } else {
+        // Lambertian
+        surface_color = direct(phit, nhit, scene, bvh, rng, |_| color * (1. / PI));
+    }
+    // Left unclamped, output formats deal with the dynamic range
+    surface_color + obj.emission_color
+}
+
+pub struct Whitted;
+
+impl Integrator for Whitted {
+    fn radiance(&self, org: Vec3<f64>, dir: Vec3<f64>, scene: &Scene, bvh: &Bvh,
+                rng: &mut XorShiftRng) -> Vec3<f64> {
+        trace(org, dir, None, scene, bvh, 0, rng)
+    }
+
+    fn radiance_differential(&self, org: Vec3<f64>, dir: Vec3<f64>, diff: &RayDifferential,
+                             scene: &Scene, bvh: &Bvh, rng: &mut XorShiftRng) -> Vec3<f64> {
+        trace(org, dir, Some(*diff), scene, bvh, 0, rng)
+    }
+}
@@ -3,170 +3,106 @@ extern crate rayon;
 extern crate rand;
 
 pub mod vec3;
+pub mod camera;
+pub mod bvh;
 pub mod solids;
 pub mod surface;
+pub mod noise;
+pub mod texture;
+pub mod differential;
+pub mod obj;
+pub mod filter;
+pub mod integrator;
+pub mod output;
+pub mod tonemap;
+pub mod error;
+pub mod framebuffer;
+pub mod scene;
+pub mod light;
+pub mod material;
 
 use surface::*;
 use vec3::*;
-use solids::Object;
-use std::fs::File;
-use std::io::BufWriter;
-use png::{Encoder, HasParameters, ColorType, BitDepth};
+use bvh::Bvh;
+use filter::Filter;
+use integrator::Integrator;
+use integrator::whitted::Whitted;
+use solids::{Object, closest_hit};
+use scene::Scene;
+use framebuffer::Framebuffer;
+use differential::RayDifferential;
+use tonemap::ToneMapping;
+use error::{Error, Result};
 use rayon::prelude::*;
 
-const MAX_DEPTH: i32 = 5;
-
-fn mix(a: f64, b: f64, mix: f64) -> f64 {b * mix + a * (1. - mix)}
-
-pub fn trace(org: Vec3<f64>, dir: Vec3<f64>, objects: &Vec<Object>, depth: i32) -> Vec3<f64> {
-    let mut tnear = ::std::f64::MAX;
-    let mut obj: Option<&Object> = None;
+pub struct RenderOptions {
+    pub width: usize,
+    pub height: usize,
+    // Samples per pixel
+    pub spp: usize,
+    pub filter: Filter,
+    pub integrator: Box<dyn Integrator + Sync>,
+    pub tone_mapping: ToneMapping
+}
 
-    for object in objects.iter() {
-        let t = match object.solid.intersect(org, dir) {
-            Some(v) => v,
-            None => {continue;}
-        };
-        if t < tnear {
-            tnear = t;
-            obj = Some(&object);
-        }
+impl RenderOptions {
+    pub fn new(width: usize, height: usize) -> Self {
+        RenderOptions {width, height, spp: 1, filter: Filter::Box,
+            integrator: Box::new(Whitted), tone_mapping: ToneMapping::default()}
     }
-    let obj = match obj {
-        // Making the background a gradient instead of a solid color
-        None => { let mut c =  Vec3::new(0.1, 0.3, 0.5) *
-                            dir.dot(&Vec3::new(0., 0., -1.)).powi(2);
-                  let intensity = c.len();
-                  return *c.normalize() * intensity;},
-        Some(o) => o
-    };
-
-    let mut surface_color: Vec3<f64> = Vec3::default();
-    let phit = org + dir * tnear;
-    let mut nhit = obj.solid.normal_at(phit, dir);
-
-    let bias = 1e-4f64;
-    let inside = if dir.dot(&nhit) > 0. {
-        nhit = -nhit;
-        true
-    } else {false};
-
-    if (obj.transparency > 0. || obj.reflection > 0.) && depth < MAX_DEPTH {
-        let facingratio = -dir.dot(&nhit);
-        let fresneleffect = mix((1. - facingratio).powi(3), 1., 0.1);
-
-        let mut refldir = dir - nhit * 2. * dir.dot(&nhit);
-        let reflection = trace(phit + nhit * bias, *refldir.normalize(), objects, depth + 1);
 
-        let mut refraction = Vec3::<f64>::default();
-        if obj.transparency > 0. {
-            let ior = 1.1;
-            let eta = if inside {ior} else {1. / ior};
-            let cosi = -nhit.dot(&dir);
-            let k = 1. - eta * eta * (1. - cosi * cosi);
-
-            let mut refrdir = dir * eta + nhit * (eta * cosi - k.sqrt());
-            refraction = trace(phit - nhit * bias, *refrdir.normalize(), objects, depth + 1);
+    fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(Error::InvalidParameter(
+                format!("image size must be positive, got {}x{}", self.width, self.height)));
         }
-        surface_color = obj.surface_color * (reflection * fresneleffect +
-                            refraction *(1. - fresneleffect) * obj.transparency);
-    } else {
-        for (i, o) in objects.iter().enumerate() {
-            if o.emission_color.x > 0. {
-                let mut light_direction = o.pos - phit;
-
-                // Light fall-off with distance
-                let dist2 = light_direction.len_sqr();
-                let val = 1. -  0.3 * dist2 / (1. + dist2.abs());
-                //let mut transmission = Vec3::new(1., 1., 1.);
-                let mut transmission = Vec3::new(val, val, val);
-
-                light_direction.normalize();
-                for (j, x) in objects.iter().enumerate() {
-                    if i != j {
-                        match x.solid.intersect(phit + nhit * bias, light_direction) {
-                            Some(_) => {transmission = Vec3::default(); break;},
-                            None => ()
-                        }
-                    }
-                }
-                surface_color = surface_color + obj.surface_color * transmission *
-                    (nhit.dot(&light_direction).max(0.)) * o.emission_color;
-            }
+        if self.spp == 0 {
+            return Err(Error::InvalidParameter("samples per pixel must be positive".to_string()));
         }
+        Ok(())
     }
-    /*
-    if surface_color.len_sqr() > 1. {
-        surface_color.normalize();
-    }*/
-    let mut color = surface_color + obj.emission_color;
-    //let intensity = color.len().min(1.);
-    if color.len_sqr() > 1. {
-        color.normalize();
-    }
-    //surface_color * intensity + obj.emission_color
-    color
 }
 
-fn get_hit_object_id(org: Vec3<f64>, dir: Vec3<f64>, objects: &Vec<Object>) -> isize {
-    let mut tnear = ::std::f64::MAX;
-    let mut id = -1;
-    for (i, o) in objects.iter().enumerate() {
-        let t = match o.solid.intersect(org, dir) {
-            Some(v) => v,
-            None => {continue;}
-        };
-        if t < tnear {
-            tnear = t;
-            id = i as isize;
-        }
+fn get_hit_object_id(org: Vec3<f64>, dir: Vec3<f64>, objects: &[Object], bvh: &Bvh) -> isize {
+    match closest_hit(objects, bvh, org, dir, 0., f64::INFINITY) {
+        Some((i, _)) => i as isize,
+        None => -1
     }
-
-    id
 }
 
-pub fn render_wireframe(width: usize, height: usize, objects: &Vec<Object>, filename: &str) {
-    let mut img = vec![Vec3::default(); width * height];
+pub fn render_wireframe(scene: &Scene, options: &RenderOptions) -> Result<Framebuffer> {
+    options.validate()?;
+    let objects = &scene.objects;
+    let (width, height) = (options.width, options.height);
+    let camera = scene.camera.with_aspect(width as f64 / height as f64);
+    let mut img = Framebuffer::new(width, height)?;
     let inv_width = 1. / (width as f64);
     let inv_height = 1. / (height as f64);
-    let fov = 50.;
-    let aspect_ratio = width as f64 * inv_height;
-    let angle = (::std::f64::consts::PI * 0.5 * fov / 180.).tan();
 
+    let bvh = Bvh::from_objects(objects);
     let mut hits = vec![-1; width * height];
 
     // Doing first row
-    let yy = (1. - 2. * (0.5 * inv_height)) * angle;
-    for x in 0..width {
-        let xx = (2. * ((x as f64 + 0.5) * inv_width) - 1.) * angle * aspect_ratio;
-        let mut dir = Vec3::new(xx, yy, -1.);
-        dir.normalize();
-        
-        hits[x] = get_hit_object_id(Vec3::default(), dir, objects);
+    for (x, hit) in hits.iter_mut().take(width).enumerate() {
+        let (org, dir) = camera.ray((x as f64 + 0.5) * inv_width, 0.5 * inv_height);
+        *hit = get_hit_object_id(org, dir, objects, &bvh);
     }
 
     // Doing first column
-    let xx = (2. * (0.5 * inv_height) - 1.) * angle * aspect_ratio;
     for y in 0..height {
-        let yy = (1. - 2. * ((y as f64 + 0.5) * inv_height)) * angle;
-        let mut dir = Vec3::new(xx, yy, -1.);
-        dir.normalize();
-        
-        hits[width * y] = get_hit_object_id(Vec3::default(), dir, objects);
+        let (org, dir) = camera.ray(0.5 * inv_width, (y as f64 + 0.5) * inv_height);
+        hits[width * y] = get_hit_object_id(org, dir, objects, &bvh);
     }
 
     for y in 1..height {
         let line = y * width;
-        let yy = (1. - 2. * ((y as f64 + 0.5) * inv_height)) * angle;
+        let sy = (y as f64 + 0.5) * inv_height;
         for x in 1..width {
-            let xx = (2. * ((x as f64 + 0.5) * inv_width) - 1.) * angle * aspect_ratio;
-            let mut dir = Vec3::new(xx, yy, -1.);
-            dir.normalize();
-            
-            let val = get_hit_object_id(Vec3::default(), dir, objects);
+            let (org, dir) = camera.ray((x as f64 + 0.5) * inv_width, sy);
+            let val = get_hit_object_id(org, dir, objects, &bvh);
             hits[line + x] = val;
             if val != hits[line + x - 1] || val != hits[line + x - width] {
-                img[line + x] = Vec3::new(1., 1., 1.);
+                img.pixels[line + x] = Vec3::new(1., 1., 1.);
             }
         }
     }
@@ -185,84 +121,66 @@ pub fn render_wireframe(width: usize, height: usize, objects: &Vec<Object>, file
     }
     */
 
-    write_to_file(width, height, &img, filename).unwrap();
+    Ok(img)
 }
 
-pub fn render(width: usize, height: usize, objects: &Vec<Object>, filename: &str) {
-    let mut img = vec![Vec3::default(); width * height];
-    //let mut pixel = &image[..];
+pub fn render(scene: &Scene, options: &RenderOptions) -> Result<Framebuffer> {
+    options.validate()?;
+    let (width, height) = (options.width, options.height);
+    let camera = &scene.camera.with_aspect(width as f64 / height as f64);
+    let mut img = Framebuffer::new(width, height)?;
     let inv_width = 1. / (width as f64);
     let inv_height = 1. / (height as f64);
-    let fov = 50.;
-    let aspect_ratio = width as f64 * inv_height;
-    let angle = (::std::f64::consts::PI * 0.5 * fov / 180.).tan();
+    let bvh = Bvh::from_objects(&scene.objects);
+    let filter = options.filter;
+    let spp = options.spp;
+    let integrator = &options.integrator;
+    // Footprints shrink with the spacing of the samples, down to an eighth
+    // of a pixel
+    let diff_scale = (1. / (spp as f64).sqrt()).max(0.125);
     {
         let mut rows: Vec<(usize, &mut [Vec3<f64>])> = 
-            img.chunks_mut(width)
+            img.rows_mut()
             .enumerate()
             .collect();
 
         rows.par_iter_mut()
             .for_each(move |&mut (y, ref mut row)| {
-                  let yy = (1. - 2. * ((y as f64 + 0.5) * inv_height)) * angle;
                   for x in 0..width {
-                      let xx = (2. * ((x as f64 + 0.5) * inv_width) - 1.) * angle * aspect_ratio;
-                      let mut dir = Vec3::new(xx, yy, -1.);
-                      dir.normalize();
-                      row[x] = trace(Vec3::default(), dir, &objects, 0);
+                      let mut rng = filter::pixel_rng(x, y);
+                      let mut samples = Vec::with_capacity(spp);
+                      // Samples are spread over the filter's support around
+                      // the pixel centre and weighted accordingly
+                      for (dx, dy) in filter::stratified_offsets(&mut rng, spp, filter.radius()) {
+                          let sx = (x as f64 + 0.5 + dx) * inv_width;
+                          let sy = (y as f64 + 0.5 + dy) * inv_height;
+                          let (org, dir) = camera.ray(sx, sy);
+                          let diff = RayDifferential::new(camera.ray(sx + inv_width, sy),
+                                                          camera.ray(sx, sy + inv_height))
+                              .scale(org, dir, diff_scale);
+                          let c = integrator.radiance_differential(org, dir, &diff, scene, &bvh,
+                                                                   &mut rng);
+                          samples.push(((dx, dy), c));
+                      }
+                      row[x] = filter.resolve(&samples);
                   }
             });
     }
-    // Single threaded version
-    /*
-    for y in 0..height {
-        let line = y * width;
-        let yy = (1. - 2. * ((y as f64 + 0.5) * inv_height)) * angle;
-        for x in 0..width {
-            let xx = (2. * ((x as f64 + 0.5) * inv_width) - 1.) * angle * aspect_ratio;
-            let mut dir = Vec3::new(xx, yy, -1.);
-            dir.normalize();
-            img[line + x] = trace(Vec3::default(), dir, objects, 0);
-        }
-    }
-    */
-
-    write_to_file(width, height, &img, filename).unwrap();
-}
-
-fn write_to_file(width: usize, height: usize, img: &Vec<Vec3<f64>>, filename: &str) ->
-    Result<(), ()> {
-
-    let mut bytes = Vec::with_capacity(width * height * 3);
-
-    for pix in img {
-        bytes.push((pix.x.min(1.) * 255.) as u8);
-        bytes.push((pix.y.min(1.) * 255.) as u8);
-        bytes.push((pix.z.min(1.) * 255.) as u8);
-    }
-
-    let w: BufWriter<Box<::std::io::Write>> = match filename {
-        "-" => BufWriter::new(Box::new(::std::io::stdout())),
-        f => BufWriter::new(Box::new(File::create(f).or_else(|_| Err(()))?))
-    };
 
-    let mut encoder = Encoder::new(w, width as u32, height as u32);
-    encoder.set(ColorType::RGB).set(BitDepth::Eight);
-    let mut writer = encoder.write_header().or_else(|_| Err(()) )?;
-    writer.write_image_data(&bytes).or_else(|_| Err(()))
+    Ok(img)
 }
 
-pub fn surface_test() {
+pub fn surface_test() -> Result<()> {
     let w = 1920;
     let h = 1080;
-    let mut img = vec![Vec3::<f64>::default(); w * h];
+    let mut img = Framebuffer::new(w, h)?;
     let s = NoiseSurface::new_seeded(Vec3::<f64>::new(1., 1., 1.), 9837502978);
 
     for y in 0..h {
         for x in 0..w {
-            img[y * w + x] = s.color_at(x as f64 / 48. - 20., y as f64 / 48.);
+            img.set(x, y, s.color_at(x as f64 / 48. - 20., y as f64 / 48.));
         }
     }
 
-    write_to_file(w, h, &img, "noise.png");
+    img.write("noise.png", &ToneMapping::default())
 }
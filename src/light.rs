@@ -0,0 +1,161 @@
+use std::str::FromStr;
+use vec3::Vec3;
+use solids::Object;
+use error::{ParseError, column_of, fields, field_count, parse_field, parse_number};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Light {
+    // Radiant intensity `intensity` in every direction
+    Point {position: Vec3<f64>, intensity: Vec3<f64>},
+    // Infinitely far away, `irradiance` on surfaces facing it. `direction` is
+    // the one light travels in.
+    Directional {direction: Vec3<f64>, irradiance: Vec3<f64>},
+    // Point light restricted to a cone around `direction`, fading out
+    // between the cosines of its inner and outer half angles
+    Spot {position: Vec3<f64>, direction: Vec3<f64>, intensity: Vec3<f64>, cos_inner: f64,
+          cos_outer: f64},
+    // Emissive scene object, by index
+    Area {object: usize}
+}
+
+// Light arriving at a point from one light sample
+pub struct LightSample {
+    // Unit direction towards the light
+    pub wi: Vec3<f64>,
+    // Distance to the light sample, infinite for directional lights
+    pub dist: f64,
+    // Irradiance on a surface facing the light, divided by the probability
+    // of the sample for area lights
+    pub irradiance: Vec3<f64>,
+    // The object sampled, for area lights
+    pub object: Option<usize>
+}
+
+fn smoothstep(e0: f64, e1: f64, x: f64) -> f64 {
+    if e0 == e1 {
+        return if x < e0 {0.} else {1.};
+    }
+    let t = ((x - e0) / (e1 - e0)).clamp(0., 1.);
+    t * t * (3. - 2. * t)
+}
+
+impl Light {
+    // Light arriving at `p` from a point of the light chosen with `u` and `v`
+    // in [0, 1). None when no light reaches `p`.
+    pub fn sample(&self, p: Vec3<f64>, objects: &[Object], u: f64, v: f64)
+        -> Option<LightSample> {
+        match *self {
+            Light::Point {position, intensity} => {
+                let mut wi = position - p;
+                let dist2 = wi.len_sqr();
+                if dist2 < 1e-12 {
+                    return None;
+                }
+                wi.normalize();
+                Some(LightSample {wi, dist: dist2.sqrt(), irradiance: intensity * (1. / dist2),
+                    object: None})
+            },
+            Light::Directional {direction, irradiance} => {
+                Some(LightSample {wi: -direction, dist: f64::INFINITY, irradiance, object: None})
+            },
+            Light::Spot {position, direction, intensity, cos_inner, cos_outer} => {
+                let mut wi = position - p;
+                let dist2 = wi.len_sqr();
+                if dist2 < 1e-12 {
+                    return None;
+                }
+                wi.normalize();
+                let falloff = smoothstep(cos_outer, cos_inner, -wi.dot(&direction));
+                if falloff <= 0. {
+                    return None;
+                }
+                Some(LightSample {wi, dist: dist2.sqrt(),
+                    irradiance: intensity * (falloff / dist2), object: None})
+            },
+            Light::Area {object} => {
+                let o = &objects[object];
+                let (lp, _, pdf) = o.solid.sample_from(p, u, v)?;
+                let mut wi = lp - p;
+                let dist = wi.len();
+                wi.normalize();
+                if pdf <= 0. || !pdf.is_finite() {
+                    return None;
+                }
+                Some(LightSample {wi, dist, irradiance: o.emission_color * (1. / pdf),
+                    object: Some(object)})
+            }
+        }
+    }
+}
+
+// point, position, color, intensity
+// directional, direction, color, irradiance
+// spot, position, direction, color, intensity, outer angle, inner angle
+// with colors in [0, 255] and half angles in degrees
+impl FromStr for Light {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = fields(s);
+        let color = |i: usize| -> Result<Vec3<f64>, ParseError> {
+            let c = parse_field::<Vec3<f64>>(s, vals[i]).map_err(|e| e.context("light color"))?;
+            let k: f64 = parse_number(s, vals[i + 1], "light intensity")?;
+            Ok(c * (k / 255.))
+        };
+        let direction = |i: usize| -> Result<Vec3<f64>, ParseError> {
+            let mut d: Vec3<f64> = parse_field(s, vals[i])?;
+            if d.len_sqr() == 0. {
+                return Err(ParseError::new("Expected a non zero direction", vals[i])
+                           .at_column(column_of(s, vals[i])));
+            }
+            Ok(*d.normalize())
+        };
+        match vals[0] {
+            "point" => {
+                if vals.len() != 4 {
+                    return Err(field_count(s, 4, "point light"));
+                }
+                Ok(Light::Point {position: parse_field(s, vals[1])?, intensity: color(2)?})
+            },
+            "directional" => {
+                if vals.len() != 4 {
+                    return Err(field_count(s, 4, "directional light"));
+                }
+                Ok(Light::Directional {direction: direction(1)?, irradiance: color(2)?})
+            },
+            "spot" => {
+                if vals.len() != 7 {
+                    return Err(field_count(s, 7, "spot light"));
+                }
+                let outer: f64 = parse_number(s, vals[5], "cone angle")?;
+                let inner: f64 = parse_number(s, vals[6], "cone angle")?;
+                Ok(Light::Spot {position: parse_field(s, vals[1])?, direction: direction(2)?,
+                    intensity: color(3)?, cos_inner: inner.min(outer).to_radians().cos(),
+                    cos_outer: outer.to_radians().cos()})
+            },
+            t => Err(ParseError::new("Unknown light type, expected point, directional or spot", t)
+                     .at_column(column_of(s, t)))
+        }
+    }
+}
+
+#[test]
+fn light_test() {
+    let p = Vec3::new(0., 0., 0.);
+    let point: Light = "point, 0 2 0, 255 255 255, 4".parse().unwrap();
+    let s = point.sample(p, &[], 0., 0.).unwrap();
+    assert_eq!((s.wi, s.dist, s.irradiance), (Vec3::new(0., 1., 0.), 2., Vec3::new(1., 1., 1.)));
+    // No direction to the light at its position
+    assert!(point.sample(Vec3::new(0., 2., 0.), &[], 0., 0.).is_none());
+
+    // Full intensity inside the inner cone, none outside the outer one
+    let spot: Light = "spot, 0 2 0, 0 -1 0, 0 0 255, 4, 30, 20".parse().unwrap();
+    assert_eq!(spot.sample(p, &[], 0., 0.).unwrap().irradiance, Vec3::new(0., 0., 1.));
+    assert!(spot.sample(Vec3::new(2., 0., 0.), &[], 0., 0.).is_none());
+    let edge = spot.sample(Vec3::new(2. * 25f64.to_radians().tan(), 0., 0.), &[], 0., 0.);
+    assert!(edge.unwrap().irradiance.z > 0.);
+
+    match "spot, 0 2 0, 0 0 0, 255 255 255, 1, 30, 20".parse::<Light>() {
+        Err(e) => {assert_eq!((e.column, e.token.as_str()), (14, "0 0 0"));},
+        _ => panic!("expected a parse error")
+    }
+}
@@ -0,0 +1,551 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+use vec3::{Vec3, basis, refract};
+use error::{ParseError, column_of, fields, field_count, parse_field, parse_number};
+
+// Roughness below which lobes are treated as perfectly specular
+const SMOOTH: f64 = 0.01;
+
+// Scattering models, coloured by the object's surface. Roughnesses are
+// perceptual, in [0, 1], and squared into GGX widths.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Material {
+    // Lambertian reflector
+    Diffuse,
+    // Metal with the complex index of refraction `eta` + i `k` per channel
+    Conductor {eta: Vec3<f64>, k: Vec3<f64>, roughness: f64},
+    // Glass, water... transmission being tinted by the surface colour
+    Dielectric {ior: f64, roughness: f64},
+    // Blend of a plastic, diffuse with a clear coat, and a metal reflecting
+    // the surface colour
+    Principled {metallic: f64, roughness: f64}
+}
+
+// Scattered direction and its throughput, the BSDF times the cosine divided
+// by the probability of the sample
+pub struct BsdfSample {
+    pub wi: Vec3<f64>,
+    pub weight: Vec3<f64>,
+    // From a perfectly specular lobe, which `Bsdf::eval` leaves out
+    pub delta: bool
+}
+
+// A material at a surface point. Directions point away from the surface and
+// are turned to the frame of the normal, which faces the incoming ray.
+pub struct Bsdf {
+    pub material: Material,
+    pub color: Vec3<f64>,
+    // Ratio of the indices of refraction on the incident and transmitted
+    // sides, as for `refract`
+    pub eta: f64,
+    pub fresnel: Fresnel,
+    pub frame: (Vec3<f64>, Vec3<f64>, Vec3<f64>)
+}
+
+// Reflectance of dielectric interfaces
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fresnel {
+    Exact,
+    // Schlick's approximation, within a few hundredths of the exact values
+    Schlick
+}
+
+impl Fresnel {
+    // Reflected fraction of light hitting the interface at `cos_i`, `eta`
+    // being the ratio of the indices of refraction. 1 on total internal
+    // reflection.
+    pub fn eval(&self, cos_i: f64, eta: f64) -> f64 {
+        match *self {
+            Fresnel::Exact => fresnel_dielectric(cos_i, eta),
+            Fresnel::Schlick => {
+                let cos_i = cos_i.clamp(0., 1.);
+                let sin2_t = eta * eta * (1. - cos_i * cos_i);
+                if sin2_t >= 1. {
+                    return 1.;
+                }
+                // Leaving the denser medium, the transmitted angle is the
+                // wider one
+                let cos = if eta > 1. {(1. - sin2_t).sqrt()} else {cos_i};
+                let r0 = ((1. - eta) / (1. + eta)).powi(2);
+                r0 + (1. - r0) * (1. - cos).powi(5)
+            }
+        }
+    }
+}
+
+impl FromStr for Fresnel {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "exact" => Ok(Fresnel::Exact),
+            "schlick" => Ok(Fresnel::Schlick),
+            t => Err(ParseError::new("Unknown Fresnel model, expected exact or schlick", t)
+                     .at_column(column_of(s, t)))
+        }
+    }
+}
+
+// Exact reflectance of an interface between dielectrics, `eta` being the
+// ratio of the indices of refraction. 1 on total internal reflection.
+pub fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = cos_i.clamp(0., 1.);
+    let sin2_t = eta * eta * (1. - cos_i * cos_i);
+    if sin2_t >= 1. {
+        return 1.;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    let rs = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let rp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (rs * rs + rp * rp) / 2.
+}
+
+// Exact reflectance of a conductor with index of refraction `eta` + i `k`
+// seen from the vacuum
+pub fn fresnel_conductor(cos_i: f64, eta: f64, k: f64) -> f64 {
+    let c2 = cos_i.clamp(0., 1.).powi(2);
+    let s2 = 1. - c2;
+    let t0 = eta * eta - k * k - s2;
+    let a2b2 = (t0 * t0 + 4. * eta * eta * k * k).sqrt();
+    let a = (0.5 * (a2b2 + t0)).max(0.).sqrt();
+    let t1 = a2b2 + c2;
+    let t2 = 2. * cos_i.clamp(0., 1.) * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = c2 * a2b2 + s2 * s2;
+    let t4 = t2 * s2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    (rs + rp) / 2.
+}
+
+fn schlick(f0: Vec3<f64>, cos: f64) -> Vec3<f64> {
+    let w = (1. - cos.clamp(0., 1.)).powi(5);
+    f0 * (1. - w) + Vec3::new(w, w, w)
+}
+
+fn alpha(roughness: f64) -> f64 {
+    (roughness * roughness).max(SMOOTH * SMOOTH)
+}
+
+fn normalized(v: Vec3<f64>) -> Vec3<f64> {
+    let mut v = v;
+    *v.normalize()
+}
+
+fn mirror(wo: Vec3<f64>, h: Vec3<f64>) -> Vec3<f64> {
+    h * (2. * wo.dot(&h)) - wo
+}
+
+// GGX distribution of normals, with the Smith height correlated shadowing.
+// Vectors are in the local frame.
+fn ggx_d(h: Vec3<f64>, alpha: f64) -> f64 {
+    if h.z <= 0. {
+        return 0.;
+    }
+    let a2 = alpha * alpha;
+    let d = h.z * h.z * (a2 - 1.) + 1.;
+    a2 / (PI * d * d)
+}
+
+fn ggx_lambda(w: Vec3<f64>, alpha: f64) -> f64 {
+    let c2 = w.z * w.z;
+    if c2 == 0. {
+        return f64::INFINITY;
+    }
+    let tan2 = (1. - c2).max(0.) / c2;
+    ((1. + alpha * alpha * tan2).sqrt() - 1.) / 2.
+}
+
+fn ggx_g1(w: Vec3<f64>, alpha: f64) -> f64 {
+    1. / (1. + ggx_lambda(w, alpha))
+}
+
+fn ggx_g(wo: Vec3<f64>, wi: Vec3<f64>, alpha: f64) -> f64 {
+    1. / (1. + ggx_lambda(wo, alpha) + ggx_lambda(wi, alpha))
+}
+
+// Normal visible from `wo` (Heitz, "Sampling the GGX Distribution of Visible
+// Normals")
+fn ggx_sample(wo: Vec3<f64>, alpha: f64, u: f64, v: f64) -> Vec3<f64> {
+    let vh = normalized(Vec3::new(alpha * wo.x, alpha * wo.y, wo.z));
+    let lensq = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if lensq > 0. {Vec3::new(-vh.y, vh.x, 0.) * (1. / lensq.sqrt())}
+             else {Vec3::new(1., 0., 0.)};
+    let t2 = vh.cross(t1);
+    let r = u.sqrt();
+    let phi = 2. * PI * v;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1. + vh.z);
+    let p2 = (1. - s) * (1. - p1 * p1).max(0.).sqrt() + s * r * phi.sin();
+    let nh = t1 * p1 + t2 * p2 + vh * (1. - p1 * p1 - p2 * p2).max(0.).sqrt();
+    normalized(Vec3::new(alpha * nh.x, alpha * nh.y, nh.z.max(1e-9)))
+}
+
+// Density of the reflected `wi` when sampling `ggx_sample`
+fn ggx_reflect_pdf(wo: Vec3<f64>, wi: Vec3<f64>, alpha: f64) -> f64 {
+    let h = normalized(wo + wi);
+    let cos = wo.dot(&h);
+    if cos <= 0. {
+        return 0.;
+    }
+    ggx_g1(wo, alpha) * ggx_d(h, alpha) / (4. * wo.z)
+}
+
+impl Material {
+    // At a surface with normal `n` facing the incoming ray and texture colour
+    // `color`, `inside` telling whether the ray comes from within the object
+    pub fn bsdf(&self, n: Vec3<f64>, color: Vec3<f64>, inside: bool, fresnel: Fresnel) -> Bsdf {
+        let eta = match *self {
+            Material::Dielectric {ior, ..} => if inside {ior} else {1. / ior},
+            _ => 1.
+        };
+        let (t, b) = basis(n);
+        Bsdf {material: *self, color, eta, fresnel, frame: (t, b, n)}
+    }
+}
+
+impl Bsdf {
+    fn to_local(&self, v: Vec3<f64>) -> Vec3<f64> {
+        let (t, b, n) = self.frame;
+        Vec3::new(v.dot(&t), v.dot(&b), v.dot(&n))
+    }
+
+    fn to_world(&self, v: Vec3<f64>) -> Vec3<f64> {
+        let (t, b, n) = self.frame;
+        t * v.x + b * v.y + n * v.z
+    }
+
+    // Whether all the light is scattered by specular lobes, light sampling
+    // being useless then
+    pub fn is_delta(&self) -> bool {
+        match self.material {
+            Material::Conductor {roughness, ..} | Material::Dielectric {roughness, ..} =>
+                roughness < SMOOTH,
+            _ => false
+        }
+    }
+
+    fn conductor_fresnel(&self, cos: f64) -> Vec3<f64> {
+        match self.material {
+            Material::Conductor {eta, k, ..} => Vec3::new(fresnel_conductor(cos, eta.x, k.x),
+                fresnel_conductor(cos, eta.y, k.y), fresnel_conductor(cos, eta.z, k.z)),
+            _ => Vec3::default()
+        }
+    }
+
+    // Reflectance at normal incidence of the principled coat and metal
+    fn principled_f0(&self, metallic: f64) -> Vec3<f64> {
+        let d = 0.04 * (1. - metallic);
+        self.color * metallic + Vec3::new(d, d, d)
+    }
+
+    // Chance of sampling the principled specular lobe over the diffuse one
+    fn principled_specular_chance(metallic: f64) -> f64 {
+        0.5 + 0.5 * metallic
+    }
+
+    // Value of the non specular lobes for light arriving from `wi` and
+    // leaving towards `wo`
+    pub fn eval(&self, wo: Vec3<f64>, wi: Vec3<f64>) -> Vec3<f64> {
+        let (wo, wi) = (self.to_local(wo), self.to_local(wi));
+        if wo.z <= 0. || wi.z == 0. {
+            return Vec3::default();
+        }
+        match self.material {
+            Material::Diffuse => {
+                if wi.z < 0. {Vec3::default()} else {self.color * (1. / PI)}
+            },
+            Material::Conductor {roughness, ..} => {
+                if roughness < SMOOTH || wi.z < 0. {
+                    return Vec3::default();
+                }
+                let a = alpha(roughness);
+                let h = normalized(wo + wi);
+                self.color * self.conductor_fresnel(wo.dot(&h)) *
+                    (ggx_d(h, a) * ggx_g(wo, wi, a) / (4. * wo.z * wi.z))
+            },
+            Material::Dielectric {roughness, ..} => {
+                if roughness < SMOOTH {
+                    return Vec3::default();
+                }
+                let a = alpha(roughness);
+                if wi.z > 0. {
+                    let h = normalized(wo + wi);
+                    let f = self.fresnel.eval(wo.dot(&h), self.eta) * ggx_d(h, a) *
+                        ggx_g(wo, wi, a) / (4. * wo.z * wi.z);
+                    return Vec3::new(f, f, f);
+                }
+                // Walter et al., "Microfacet Models for Refraction through
+                // Rough Surfaces", without the radiance compression that
+                // cancels out through closed objects
+                let e = 1. / self.eta;
+                let mut h = normalized(-(wo + wi * e));
+                if h.z < 0. {
+                    h = -h;
+                }
+                let (cos_o, cos_i) = (wo.dot(&h), wi.dot(&h));
+                if cos_o <= 0. || cos_i >= 0. {
+                    return Vec3::default();
+                }
+                let denom = cos_o + e * cos_i;
+                let f = (1. - self.fresnel.eval(cos_o, self.eta)) * ggx_d(h, a) *
+                    ggx_g(wo, wi, a) * e * e * cos_o * -cos_i / (wo.z * -wi.z * denom * denom);
+                self.color * f
+            },
+            Material::Principled {metallic, roughness} => {
+                if wi.z < 0. {
+                    return Vec3::default();
+                }
+                let h = normalized(wo + wi);
+                let f = schlick(self.principled_f0(metallic), wo.dot(&h));
+                let diffuse = self.color * (Vec3::new(1., 1., 1.) - f) *
+                    ((1. - metallic) / PI);
+                if roughness < SMOOTH {
+                    return diffuse;
+                }
+                let a = alpha(roughness);
+                diffuse + f * (ggx_d(h, a) * ggx_g(wo, wi, a) / (4. * wo.z * wi.z))
+            }
+        }
+    }
+
+    // Density of `eval`'s lobes as sampled by `sample`, for the principled
+    // material whose lobes are mixed
+    fn principled_pdf(&self, wo: Vec3<f64>, wi: Vec3<f64>, metallic: f64, roughness: f64)
+        -> f64 {
+        let p = Bsdf::principled_specular_chance(metallic);
+        let diffuse = (1. - p) * wi.z.max(0.) / PI;
+        if roughness < SMOOTH {
+            diffuse
+        } else {
+            p * ggx_reflect_pdf(wo, wi, alpha(roughness)) + diffuse
+        }
+    }
+
+    // Perfectly specular lobes leaving towards `wo`, with their weights
+    pub fn specular(&self, wo: Vec3<f64>) -> Vec<BsdfSample> {
+        let wo = self.to_local(wo);
+        if wo.z <= 0. {
+            return Vec::new();
+        }
+        let mirrored = self.to_world(Vec3::new(-wo.x, -wo.y, wo.z));
+        let lobe = |wi, weight| BsdfSample {wi, weight, delta: true};
+        match self.material {
+            Material::Conductor {roughness, ..} if roughness < SMOOTH => {
+                vec![lobe(mirrored, self.color * self.conductor_fresnel(wo.z))]
+            },
+            Material::Dielectric {roughness, ..} if roughness < SMOOTH => {
+                let f = self.fresnel.eval(wo.z, self.eta);
+                let mut lobes = vec![lobe(mirrored, Vec3::new(f, f, f))];
+                if let Some(t) = refract(-wo, Vec3::new(0., 0., 1.), self.eta) {
+                    lobes.push(lobe(self.to_world(t), self.color * (1. - f)));
+                }
+                lobes
+            },
+            Material::Principled {metallic, roughness} if roughness < SMOOTH => {
+                vec![lobe(mirrored, schlick(self.principled_f0(metallic), wo.z))]
+            },
+            _ => Vec::new()
+        }
+    }
+
+    // Direction of light scattered towards `wo`, picked with `u`, `v` and
+    // `w` in [0, 1). None when the sample carries no light.
+    pub fn sample(&self, wo: Vec3<f64>, u: f64, v: f64, w: f64) -> Option<BsdfSample> {
+        let world = wo;
+        let wo = self.to_local(wo);
+        if wo.z <= 0. {
+            return None;
+        }
+        let out = |wi: Vec3<f64>, weight, delta| {
+            Some(BsdfSample {wi: self.to_world(wi), weight, delta})
+        };
+        let cosine = || {
+            let r = u.sqrt();
+            let phi = 2. * PI * v;
+            Vec3::new(r * phi.cos(), r * phi.sin(), (1. - u).max(0.).sqrt())
+        };
+        match self.material {
+            Material::Diffuse => {
+                // The cosine and pdf cancel out with the lambertian BRDF
+                out(cosine(), self.color, false)
+            },
+            Material::Conductor {roughness, ..} => {
+                if roughness < SMOOTH {
+                    return self.specular(world).pop();
+                }
+                let a = alpha(roughness);
+                let h = ggx_sample(wo, a, u, v);
+                let wi = mirror(wo, h);
+                if wi.z <= 0. {
+                    return None;
+                }
+                out(wi, self.color * self.conductor_fresnel(wo.dot(&h)) *
+                    (ggx_g(wo, wi, a) / ggx_g1(wo, a)), false)
+            },
+            Material::Dielectric {roughness, ..} => {
+                let smooth = roughness < SMOOTH;
+                let a = alpha(roughness);
+                let h = if smooth {Vec3::new(0., 0., 1.)} else {ggx_sample(wo, a, u, v)};
+                // Reflection or transmission in proportion to the Fresnel
+                // term, which the weights then leave out
+                let f = self.fresnel.eval(wo.dot(&h), self.eta);
+                let (wi, tint) = if w < f {
+                    (mirror(wo, h), Vec3::new(1., 1., 1.))
+                } else {
+                    (refract(-wo, h, self.eta)?, self.color)
+                };
+                if (wi.z > 0.) != (w < f) || wi.z == 0. {
+                    return None;
+                }
+                let g = if smooth {1.} else {ggx_g(wo, wi, a) / ggx_g1(wo, a)};
+                out(wi, tint * g, smooth)
+            },
+            Material::Principled {metallic, roughness} => {
+                let p = Bsdf::principled_specular_chance(metallic);
+                if w < p {
+                    if roughness < SMOOTH {
+                        let f0 = self.principled_f0(metallic);
+                        return out(Vec3::new(-wo.x, -wo.y, wo.z),
+                                   schlick(f0, wo.z) * (1. / p), true);
+                    }
+                    let wi = mirror(wo, ggx_sample(wo, alpha(roughness), u, v));
+                    if wi.z <= 0. {
+                        return None;
+                    }
+                    let pdf = self.principled_pdf(wo, wi, metallic, roughness);
+                    out(wi, self.eval(world, self.to_world(wi)) * (wi.z / pdf), false)
+                } else {
+                    let wi = cosine();
+                    let pdf = self.principled_pdf(wo, wi, metallic, roughness);
+                    if pdf <= 0. {
+                        return None;
+                    }
+                    out(wi, self.eval(world, self.to_world(wi)) * (wi.z / pdf), false)
+                }
+            }
+        }
+    }
+}
+
+// Indices of refraction and extinction coefficients of a few metals at
+// 650, 550 and 450nm, for red, green and blue
+fn metal(name: &str) -> Option<(Vec3<f64>, Vec3<f64>)> {
+    match name {
+        "gold" => Some((Vec3::new(0.143, 0.374, 1.442), Vec3::new(3.983, 2.385, 1.603))),
+        "silver" => Some((Vec3::new(0.155, 0.117, 0.138), Vec3::new(4.828, 3.122, 2.147))),
+        "copper" => Some((Vec3::new(0.200, 0.924, 1.102), Vec3::new(3.912, 2.452, 2.142))),
+        "aluminium" => Some((Vec3::new(1.657, 0.880, 0.521), Vec3::new(9.224, 6.270, 4.837))),
+        _ => None
+    }
+}
+
+// diffuse
+// conductor, gold|silver|copper|aluminium, roughness
+// conductor, eta, k, roughness
+// dielectric, ior, roughness
+// principled, metallic, roughness
+// with roughness and metallic in [0, 1]
+impl FromStr for Material {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = fields(s);
+        let unit = |i: usize, what: &str| -> Result<f64, ParseError> {
+            let x: f64 = parse_number(s, vals[i], what)?;
+            if !(0. ..=1.).contains(&x) {
+                return Err(ParseError::new(&format!("Expected a {} in [0, 1]", what), vals[i])
+                           .at_column(column_of(s, vals[i])));
+            }
+            Ok(x)
+        };
+        match vals[0] {
+            "diffuse" => {
+                if vals.len() != 1 {
+                    return Err(field_count(s, 1, "diffuse material"));
+                }
+                Ok(Material::Diffuse)
+            },
+            "conductor" => {
+                let (eta, k) = match (vals.len(), vals.get(1).and_then(|v| metal(v))) {
+                    (3, Some(m)) => m,
+                    (4, _) => (parse_field(s, vals[1])?, parse_field(s, vals[2])?),
+                    (3, None) => {
+                        return Err(ParseError::new(
+                            "Unknown metal, expected gold, silver, copper or aluminium", vals[1])
+                            .at_column(column_of(s, vals[1])));
+                    },
+                    _ => {return Err(field_count(s, 4, "conductor material"));}
+                };
+                Ok(Material::Conductor {eta, k, roughness: unit(vals.len() - 1, "roughness")?})
+            },
+            "dielectric" => {
+                if vals.len() != 3 {
+                    return Err(field_count(s, 3, "dielectric material"));
+                }
+                let ior: f64 = parse_number(s, vals[1], "index of refraction")?;
+                if ior <= 0. {
+                    return Err(ParseError::new("Expected a positive index of refraction", vals[1])
+                               .at_column(column_of(s, vals[1])));
+                }
+                Ok(Material::Dielectric {ior, roughness: unit(2, "roughness")?})
+            },
+            "principled" => {
+                if vals.len() != 3 {
+                    return Err(field_count(s, 3, "principled material"));
+                }
+                Ok(Material::Principled {metallic: unit(1, "metallic factor")?,
+                    roughness: unit(2, "roughness")?})
+            },
+            t => Err(ParseError::new(
+                     "Unknown material, expected diffuse, conductor, dielectric or principled", t)
+                     .at_column(column_of(s, t)))
+        }
+    }
+}
+
+#[test]
+fn material_test() {
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    // Glass reflects 4% at normal incidence, everything past the critical
+    // angle, and conductors beat dielectrics
+    assert!((fresnel_dielectric(1., 1. / 1.5) - 0.04).abs() < 1e-9);
+    assert_eq!(fresnel_dielectric(0.5, 1.5), 1.);
+    let (eta, k) = metal("gold").unwrap();
+    assert!(fresnel_conductor(1., eta.x, k.x) > 0.9);
+    // Schlick agrees at normal incidence and on total internal reflection,
+    // and is close elsewhere
+    assert!((Fresnel::Schlick.eval(1., 1.5) - 0.04).abs() < 1e-9);
+    assert_eq!(Fresnel::Schlick.eval(0.5, 1.5), 1.);
+    for &cos in &[0.05, 0.2, 0.5, 0.9] {
+        let (s, e) = (Fresnel::Schlick.eval(cos, 1. / 1.5), Fresnel::Exact.eval(cos, 1. / 1.5));
+        assert!((s - e).abs() < 0.04);
+    }
+
+    // Sample weights average to the albedo integrated from `eval`
+    let n = Vec3::new(0., 0., 1.);
+    let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+    for def in &["conductor, silver, 0.3", "principled, 0.5, 0.4", "dielectric, 1.5, 0.5"] {
+        let m: Material = def.parse().unwrap();
+        let bsdf = m.bsdf(n, Vec3::new(1., 1., 1.), false, Fresnel::Exact);
+        let wo = normalized(Vec3::new(0.3, 0., 1.));
+        let (mut sampled, mut integrated) = (0., 0.);
+        let count = 200000;
+        for _ in 0..count {
+            if let Some(s) = bsdf.sample(wo, rng.gen(), rng.gen(), rng.gen()) {
+                sampled += s.weight.y;
+            }
+            // Uniform sphere directions
+            let z = 1. - 2. * rng.gen::<f64>();
+            let phi = 2. * PI * rng.gen::<f64>();
+            let r = (1. - z * z).sqrt();
+            let wi = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+            integrated += bsdf.eval(wo, wi).y * z.abs() * 4. * PI;
+        }
+        let (sampled, integrated) = (sampled / count as f64, integrated / count as f64);
+        assert!((sampled - integrated).abs() < 0.03, "{}: {} {}", def, sampled, integrated);
+    }
+
+    match "conductor, bronze, 0.2".parse::<Material>() {
+        Err(e) => {assert_eq!((e.column, e.token.as_str()), (12, "bronze"));},
+        _ => panic!("expected a parse error")
+    }
+}
@@ -0,0 +1,296 @@
+use std::str::FromStr;
+use super::vec3::Vec3;
+use super::error::ParseError;
+use super::rand::{Rng, SeedableRng, isaac::IsaacRng};
+
+// Gradient noise over a seeded permutation table, repeating every 256 units
+#[derive(Clone)]
+pub struct Noise {
+    perm: Vec<usize>
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+// One of 12 edge directions of a cube, padded to 16
+fn grad3(hash: usize, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 {x} else {y};
+    let v = if h < 4 {y} else if h == 12 || h == 14 {x} else {z};
+    (if h & 1 == 0 {u} else {-u}) + (if h & 2 == 0 {v} else {-v})
+}
+
+// One of 32 edge directions of a tesseract
+fn grad4(hash: usize, x: f64, y: f64, z: f64, w: f64) -> f64 {
+    let h = hash & 31;
+    let a = if h < 24 {x} else {y};
+    let b = if h < 16 {y} else {z};
+    let c = if h < 8 {z} else {w};
+    (if h & 1 == 0 {a} else {-a}) + (if h & 2 == 0 {b} else {-b}) +
+        (if h & 4 == 0 {c} else {-c})
+}
+
+impl Noise {
+    pub fn new_seeded(seed: u64) -> Self {
+        let s = [(seed >> 32) as u32, (seed & 0xffff_ffff) as u32];
+        let mut rng = IsaacRng::from_seed(&s);
+        let mut perm = (0..256).collect::<Vec<usize>>();
+        for k in (1..256).rev() {
+            let i = rng.gen_range(0, k + 1);
+            perm.swap(k, i);
+        }
+        let doubled = perm.iter().chain(perm.iter()).cloned().collect();
+        Noise {perm: doubled}
+    }
+
+    // Coordinates in [0, 256]
+    fn hash(&self, c: &[usize]) -> usize {
+        c.iter().fold(0, |h, &i| self.perm[h + i])
+    }
+
+    // Improved Perlin noise, roughly in [-1, 1]
+    pub fn perlin3(&self, p: Vec3<f64>) -> f64 {
+        let cell = [p.x.floor(), p.y.floor(), p.z.floor()];
+        let i = [cell[0] as i64 as usize & 255, cell[1] as i64 as usize & 255,
+                 cell[2] as i64 as usize & 255];
+        let f = [p.x - cell[0], p.y - cell[1], p.z - cell[2]];
+        let u = [fade(f[0]), fade(f[1]), fade(f[2])];
+
+        (0..8).map(|corner| {
+            let b = [corner & 1, (corner >> 1) & 1, corner >> 2];
+            let weight = (0..3).map(|k| if b[k] == 1 {u[k]} else {1. - u[k]}).product::<f64>();
+            let h = self.hash(&[i[0] + b[0], i[1] + b[1], i[2] + b[2]]);
+            weight * grad3(h, f[0] - b[0] as f64, f[1] - b[1] as f64, f[2] - b[2] as f64)
+        }).sum()
+    }
+
+    pub fn perlin4(&self, p: Vec3<f64>, w: f64) -> f64 {
+        let cell = [p.x.floor(), p.y.floor(), p.z.floor(), w.floor()];
+        let i = [cell[0] as i64 as usize & 255, cell[1] as i64 as usize & 255,
+                 cell[2] as i64 as usize & 255, cell[3] as i64 as usize & 255];
+        let f = [p.x - cell[0], p.y - cell[1], p.z - cell[2], w - cell[3]];
+        let u = [fade(f[0]), fade(f[1]), fade(f[2]), fade(f[3])];
+
+        (0..16).map(|corner| {
+            let b = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1, corner >> 3];
+            let weight = (0..4).map(|k| if b[k] == 1 {u[k]} else {1. - u[k]}).product::<f64>();
+            let h = self.hash(&[i[0] + b[0], i[1] + b[1], i[2] + b[2], i[3] + b[3]]);
+            weight * grad4(h, f[0] - b[0] as f64, f[1] - b[1] as f64, f[2] - b[2] as f64,
+                           f[3] - b[3] as f64)
+        }).sum()
+    }
+
+    // Simplex noise, see Gustavson's "Simplex noise demystified"
+    pub fn simplex3(&self, p: Vec3<f64>) -> f64 {
+        let (f3, g3) = (1. / 3., 1. / 6.);
+        let x = [p.x, p.y, p.z];
+        let s = (x[0] + x[1] + x[2]) * f3;
+        let cell = [(x[0] + s).floor(), (x[1] + s).floor(), (x[2] + s).floor()];
+        let t = (cell[0] + cell[1] + cell[2]) * g3;
+        let d0 = [x[0] - cell[0] + t, x[1] - cell[1] + t, x[2] - cell[2] + t];
+        let i = [cell[0] as i64 as usize & 255, cell[1] as i64 as usize & 255,
+                 cell[2] as i64 as usize & 255];
+
+        // The simplex corners are reached by stepping along axes in
+        // decreasing order of the offsets
+        let mut rank = [0; 3];
+        for a in 0..3 {
+            for b in a + 1..3 {
+                if d0[a] > d0[b] {rank[a] += 1} else {rank[b] += 1}
+            }
+        }
+
+        (0..4).map(|c| {
+            let o = [(rank[0] + c >= 3) as usize, (rank[1] + c >= 3) as usize,
+                     (rank[2] + c >= 3) as usize];
+            let g = c as f64 * g3;
+            let d = [d0[0] - o[0] as f64 + g, d0[1] - o[1] as f64 + g, d0[2] - o[2] as f64 + g];
+            let t = 0.6 - d[0] * d[0] - d[1] * d[1] - d[2] * d[2];
+            if t < 0. {
+                return 0.;
+            }
+            let h = self.hash(&[i[0] + o[0], i[1] + o[1], i[2] + o[2]]);
+            t * t * t * t * grad3(h, d[0], d[1], d[2])
+        }).sum::<f64>() * 32.
+    }
+
+    pub fn simplex4(&self, p: Vec3<f64>, w: f64) -> f64 {
+        let f4 = (5f64.sqrt() - 1.) / 4.;
+        let g4 = (5. - 5f64.sqrt()) / 20.;
+        let x = [p.x, p.y, p.z, w];
+        let s = (x[0] + x[1] + x[2] + x[3]) * f4;
+        let cell = [(x[0] + s).floor(), (x[1] + s).floor(), (x[2] + s).floor(),
+                    (x[3] + s).floor()];
+        let t = (cell[0] + cell[1] + cell[2] + cell[3]) * g4;
+        let d0 = [x[0] - cell[0] + t, x[1] - cell[1] + t, x[2] - cell[2] + t,
+                  x[3] - cell[3] + t];
+        let i = [cell[0] as i64 as usize & 255, cell[1] as i64 as usize & 255,
+                 cell[2] as i64 as usize & 255, cell[3] as i64 as usize & 255];
+
+        let mut rank = [0; 4];
+        for a in 0..4 {
+            for b in a + 1..4 {
+                if d0[a] > d0[b] {rank[a] += 1} else {rank[b] += 1}
+            }
+        }
+
+        (0..5).map(|c| {
+            let o = [(rank[0] + c >= 4) as usize, (rank[1] + c >= 4) as usize,
+                     (rank[2] + c >= 4) as usize, (rank[3] + c >= 4) as usize];
+            let g = c as f64 * g4;
+            let d = [d0[0] - o[0] as f64 + g, d0[1] - o[1] as f64 + g, d0[2] - o[2] as f64 + g,
+                     d0[3] - o[3] as f64 + g];
+            let t = 0.6 - d.iter().map(|v| v * v).sum::<f64>();
+            if t < 0. {
+                return 0.;
+            }
+            let h = self.hash(&[i[0] + o[0], i[1] + o[1], i[2] + o[2], i[3] + o[3]]);
+            t * t * t * t * grad4(h, d[0], d[1], d[2], d[3])
+        }).sum::<f64>() * 27.
+    }
+
+    // Worley's cellular noise, distances to the closest and second closest of
+    // a set of feature points scattered one per unit cell
+    pub fn worley3(&self, p: Vec3<f64>) -> (f64, f64) {
+        let cell = [p.x.floor(), p.y.floor(), p.z.floor()];
+        let (mut f1, mut f2) = (f64::INFINITY, f64::INFINITY);
+        for dz in -1..2 {
+            for dy in -1..2 {
+                for dx in -1..2 {
+                    let c = [cell[0] + dx as f64, cell[1] + dy as f64, cell[2] + dz as f64];
+                    let h = self.hash(&[c[0] as i64 as usize & 255, c[1] as i64 as usize & 255,
+                                        c[2] as i64 as usize & 255]);
+                    let jitter = |k: usize| (self.perm[h + k] as f64 + 0.5) / 256.;
+                    let feature = Vec3::new(c[0] + jitter(0), c[1] + jitter(1), c[2] + jitter(2));
+                    let d = (feature - p).len();
+                    if d < f1 {
+                        f2 = f1;
+                        f1 = d;
+                    } else if d < f2 {
+                        f2 = d;
+                    }
+                }
+            }
+        }
+        (f1, f2)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Basis {
+    Perlin,
+    Simplex
+}
+
+impl FromStr for Basis {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perlin" => Ok(Basis::Perlin),
+            "simplex" => Ok(Basis::Simplex),
+            _ => Err(ParseError::new("Unknown noise basis, expected perlin or simplex", s))
+        }
+    }
+}
+
+// Sums of noise octaves, each `lacunarity` times the frequency and `gain`
+// times the amplitude of the previous one
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Fractal {
+    pub basis: Basis,
+    pub octaves: usize,
+    pub lacunarity: f64,
+    pub gain: f64
+}
+
+impl Default for Fractal {
+    fn default() -> Self {
+        Fractal {basis: Basis::Perlin, octaves: 6, lacunarity: 2., gain: 0.5}
+    }
+}
+
+impl Fractal {
+    // With a `time`, noise is 4D so patterns can evolve smoothly
+    fn sample(&self, noise: &Noise, p: Vec3<f64>, time: Option<f64>) -> f64 {
+        match (self.basis, time) {
+            (Basis::Perlin, None) => noise.perlin3(p),
+            (Basis::Perlin, Some(w)) => noise.perlin4(p, w),
+            (Basis::Simplex, None) => noise.simplex3(p),
+            (Basis::Simplex, Some(w)) => noise.simplex4(p, w)
+        }
+    }
+
+    // Calls `f` with each octave's noise value and amplitude, returns the
+    // sum of the amplitudes
+    fn octaves<F>(&self, noise: &Noise, p: Vec3<f64>, time: Option<f64>, mut f: F) -> f64
+        where F: FnMut(f64, f64) {
+        let (mut freq, mut amp, mut total) = (1., 1., 0.);
+        for _ in 0..self.octaves.max(1) {
+            f(self.sample(noise, p * freq, time.map(|w| w * freq)), amp);
+            total += amp;
+            freq *= self.lacunarity;
+            amp *= self.gain;
+        }
+        total
+    }
+
+    // Fractional Brownian motion, roughly in [-1, 1]
+    pub fn fbm(&self, noise: &Noise, p: Vec3<f64>, time: Option<f64>) -> f64 {
+        let mut sum = 0.;
+        let total = self.octaves(noise, p, time, |n, amp| {sum += n * amp;});
+        sum / total
+    }
+
+    // Sum of absolute octaves, in [0, 1]
+    pub fn turbulence(&self, noise: &Noise, p: Vec3<f64>, time: Option<f64>) -> f64 {
+        let mut sum = 0.;
+        let total = self.octaves(noise, p, time, |n, amp| {sum += n.abs() * amp;});
+        (sum / total).min(1.)
+    }
+
+    // Musgrave's ridged multifractal, sharp crests where the noise crosses
+    // zero, each octave weighted by the previous one. In [0, 1].
+    pub fn ridged(&self, noise: &Noise, p: Vec3<f64>, time: Option<f64>) -> f64 {
+        let mut sum = 0.;
+        let mut weight = 1.;
+        let total = self.octaves(noise, p, time, |n, amp| {
+            let signal = (1. - n.abs()).max(0.).powi(2) * weight;
+            weight = (signal * 2.).min(1.);
+            sum += signal * amp;
+        });
+        sum / total
+    }
+}
+
+#[test]
+fn noise_test() {
+    let a = Noise::new_seeded(42);
+    let b = Noise::new_seeded(42);
+    let c = Noise::new_seeded(43);
+
+    let mut differs = false;
+    for k in 0..200 {
+        let f = k as f64;
+        let p = Vec3::new(f * 0.173 - 17., f * 0.291, -f * 0.057);
+        let w = f * 0.11;
+        for &(na, nb) in &[(a.perlin3(p), b.perlin3(p)), (a.perlin4(p, w), b.perlin4(p, w)),
+                           (a.simplex3(p), b.simplex3(p)), (a.simplex4(p, w), b.simplex4(p, w))] {
+            assert_eq!(na, nb);
+            assert!(na.abs() <= 1.1);
+        }
+        differs |= a.perlin3(p) != c.perlin3(p);
+
+        let fractal = Fractal::default();
+        let t = fractal.turbulence(&a, p, None);
+        let r = fractal.ridged(&a, p, Some(w));
+        assert!((0. ..=1.).contains(&t) && (0. ..=1.).contains(&r));
+    }
+    assert!(differs);
+
+    // Perlin noise vanishes on the lattice
+    assert_eq!(a.perlin3(Vec3::new(3., -7., 12.)), 0.);
+
+    let (f1, f2) = a.worley3(Vec3::new(0.3, -4.2, 9.9));
+    assert!(f1 <= f2 && f1 < 3f64.sqrt());
+}
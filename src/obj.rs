@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+use super::vec3::Vec3;
+use super::solids::Object;
+use super::solids::mesh::Mesh;
+use super::surface::{Surface, Bump};
+use super::material::Material;
+use super::error::{Error, ParseError, Result, column_of};
+
+pub struct ObjMaterial {
+    pub name: String,
+    pub surface_color: Vec3<f64>,
+    // Texture used instead of `surface_color`
+    pub surface: Option<Surface>,
+    pub bump: Option<Bump>,
+    pub material: Option<Material>,
+    pub emission_color: Vec3<f64>,
+    pub reflection: f64,
+    pub transparency: f64,
+    pub ior: Option<f64>
+}
+
+impl ObjMaterial {
+    pub fn new(name: &str, surface_color: Vec3<f64>, emission_color: Vec3<f64>,
+               reflection: f64, transparency: f64) -> Self {
+        ObjMaterial {name: name.to_string(), surface_color, surface: None, bump: None,
+            material: None, emission_color, reflection, transparency, ior: None}
+    }
+}
+
+pub struct ObjFace {
+    pub vertices: [usize; 3],
+    pub uvs: Option<[usize; 3]>,
+    pub normals: Option<[usize; 3]>,
+    pub material: Option<usize>
+}
+
+pub struct ObjMesh {
+    pub positions: Vec<Vec3<f64>>,
+    pub normals: Vec<Vec3<f64>>,
+    pub uvs: Vec<(f64, f64)>,
+    pub faces: Vec<ObjFace>,
+    pub materials: Vec<ObjMaterial>
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    let mut s = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut s))
+        .map_err(|e| Error::io(&path.display().to_string(), e))?;
+    Ok(s)
+}
+
+// Tokens are subslices of `line`, so errors can point at them
+struct Line<'a> {
+    text: &'a str,
+    number: usize
+}
+
+impl<'a> Line<'a> {
+    fn error(&self, message: &str, token: &str) -> ParseError {
+        ParseError::new(message, token).at(self.number, column_of(self.text, token))
+    }
+
+    fn floats(&self, tokens: &[&str]) -> ::std::result::Result<Vec<f64>, ParseError> {
+        tokens.iter()
+            .map(|t| f64::from_str(t).map_err(|_| self.error("Invalid number", t)))
+            .collect()
+    }
+
+    fn scalar(&self, tokens: &[&str]) -> ::std::result::Result<f64, ParseError> {
+        match tokens.get(1) {
+            Some(t) => f64::from_str(t).map_err(|_| self.error("Invalid number", t)),
+            None => Err(self.error("Expected a value after", tokens[0]))
+        }
+    }
+
+    fn vec3(&self, tokens: &[&str]) -> ::std::result::Result<Vec3<f64>, ParseError> {
+        let vals = self.floats(&tokens[1..])?;
+        if vals.len() < 3 {
+            return Err(self.error("Expected 3 values after", tokens[0]));
+        }
+        Ok(Vec3::new(vals[0], vals[1], vals[2]))
+    }
+
+    // OBJ indices are 1-based, negative ones are relative to the end of the list
+    fn index(&self, s: &str, len: usize) -> ::std::result::Result<usize, ParseError> {
+        let i = isize::from_str(s).map_err(|_| self.error("Invalid index", s))?;
+        let idx = if i < 0 {len as isize + i} else {i - 1};
+        if idx < 0 || idx as usize >= len {
+            return Err(self.error("Index out of range", s));
+        }
+        Ok(idx as usize)
+    }
+}
+
+fn is_ear(points: &[Vec3<f64>], poly: &[usize], i: usize, normal: Vec3<f64>) -> bool {
+    let n = poly.len();
+    let a = points[poly[(i + n - 1) % n]];
+    let b = points[poly[i]];
+    let c = points[poly[(i + 1) % n]];
+    if (b - a).cross(c - b).dot(&normal) <= 0. {
+        return false;
+    }
+    // No other vertex may lie inside the candidate triangle
+    let inside = |p: Vec3<f64>| {
+        (b - a).cross(p - a).dot(&normal) >= 0. &&
+        (c - b).cross(p - b).dot(&normal) >= 0. &&
+        (a - c).cross(p - c).dot(&normal) >= 0.
+    };
+    (0..n).filter(|&j| j != i && j != (i + n - 1) % n && j != (i + 1) % n)
+        .all(|j| !inside(points[poly[j]]))
+}
+
+// Ear clipping, returns triangles as indices into `points`
+fn triangulate(points: &[Vec3<f64>]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+
+    // Newell's method is robust to non-planar and concave polygons
+    let mut normal = Vec3::default();
+    for i in 0..n {
+        normal = normal + points[i].cross(points[(i + 1) % n]);
+    }
+
+    let mut poly = (0..n).collect::<Vec<usize>>();
+    let mut tris = Vec::with_capacity(n - 2);
+    while poly.len() > 3 {
+        match (0..poly.len()).find(|&i| is_ear(points, &poly, i, normal)) {
+            Some(i) => {
+                let l = poly.len();
+                tris.push([poly[(i + l - 1) % l], poly[i], poly[(i + 1) % l]]);
+                poly.remove(i);
+            },
+            // Degenerate polygon, fall back to a fan
+            None => {
+                for i in 1..poly.len() - 1 {
+                    tris.push([poly[0], poly[i], poly[i + 1]]);
+                }
+                return tris;
+            }
+        }
+    }
+    tris.push([poly[0], poly[1], poly[2]]);
+    tris
+}
+
+pub fn parse_mtl(src: &str) -> ::std::result::Result<Vec<ObjMaterial>, ParseError> {
+    let mut materials: Vec<ObjMaterial> = Vec::new();
+    let mut specular = Vec3::default();
+    let mut illum = 2;
+
+    for (i, text) in src.lines().enumerate() {
+        let line = Line {text, number: i + 1};
+        let tokens = text.split_whitespace().collect::<Vec<&str>>();
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
+        if tokens[0] == "newmtl" {
+            materials.push(ObjMaterial::new(&tokens[1..].join(" "), Vec3::new(0.8, 0.8, 0.8),
+                                            Vec3::default(), 0., 0.));
+            specular = Vec3::default();
+            illum = 2;
+            continue;
+        }
+        let mat = match materials.last_mut() {
+            Some(m) => m,
+            None => {return Err(line.error("Material property before newmtl", tokens[0]));}
+        };
+        match tokens[0] {
+            "Kd" => {mat.surface_color = line.vec3(&tokens)?;},
+            "Ke" => {mat.emission_color = line.vec3(&tokens)?;},
+            "Ks" => {specular = line.vec3(&tokens)?;},
+            "d" => {mat.transparency = 1. - line.scalar(&tokens)?;},
+            "Tr" => {mat.transparency = line.scalar(&tokens)?;},
+            "Ni" => {mat.ior = Some(line.scalar(&tokens)?);},
+            "illum" => {illum = line.scalar(&tokens)? as i32;},
+            _ => ()
+        }
+        // Only the raytraced illumination models are reflective
+        mat.reflection = if illum >= 3 {
+            specular.x.max(specular.y).max(specular.z)
+        } else {
+            0.
+        };
+    }
+
+    Ok(materials)
+}
+
+impl ObjMesh {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let file = Path::new(path);
+        let dir = file.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&read_file(file)?, dir).map_err(|e| match e {
+            Error::Parse(ref p) if p.file.is_none() => Error::Parse(p.clone().in_file(path)),
+            e => e
+        })
+    }
+
+    // `dir` is where material libraries are looked up
+    pub fn parse(src: &str, dir: &Path) -> Result<Self> {
+        let mut mesh = ObjMesh {positions: Vec::new(), normals: Vec::new(), uvs: Vec::new(),
+            faces: Vec::new(), materials: Vec::new()};
+        let mut material = None;
+
+        for (i, text) in src.lines().enumerate() {
+            let line = Line {text, number: i + 1};
+            let tokens = text.split_whitespace().collect::<Vec<&str>>();
+            if tokens.is_empty() || tokens[0].starts_with('#') {
+                continue;
+            }
+            match tokens[0] {
+                "v" => {mesh.positions.push(line.vec3(&tokens)?);},
+                "vn" => {mesh.normals.push(*line.vec3(&tokens)?.normalize());},
+                "vt" => {
+                    let vals = line.floats(&tokens[1..])?;
+                    if vals.is_empty() {
+                        return Err(line.error("Expected texture coordinates after",
+                                              tokens[0]).into());
+                    }
+                    mesh.uvs.push((vals[0], *vals.get(1).unwrap_or(&0.)));
+                },
+                "f" => {mesh.parse_face(&line, &tokens, material)?;},
+                "mtllib" => {
+                    for name in &tokens[1..] {
+                        let path = dir.join(name);
+                        mesh.materials.extend(parse_mtl(&read_file(&path)?)
+                            .map_err(|e| e.in_file(&path.display().to_string()))?);
+                    }
+                },
+                "usemtl" => {
+                    let name = tokens[1..].join(" ");
+                    material = mesh.materials.iter().position(|m| m.name == name);
+                },
+                // Groups, objects and smoothing groups are irrelevant here
+                _ => ()
+            }
+        }
+
+        if mesh.faces.is_empty() {
+            let trimmed = src.trim();
+            return Err(ParseError::new("Expected at least one face in OBJ data",
+                                       trimmed.lines().next().unwrap_or("")).into());
+        }
+        Ok(mesh)
+    }
+
+    fn parse_face(&mut self, line: &Line, tokens: &[&str], material: Option<usize>)
+        -> ::std::result::Result<(), ParseError> {
+        if tokens.len() < 4 {
+            return Err(line.error("Face with less than 3 vertices", line.text.trim()));
+        }
+        let tokens = &tokens[1..];
+
+        let mut v = Vec::with_capacity(tokens.len());
+        let mut vt = Vec::with_capacity(tokens.len());
+        let mut vn = Vec::with_capacity(tokens.len());
+        for t in tokens {
+            let mut parts = t.split('/');
+            v.push(line.index(parts.next().unwrap_or(t), self.positions.len())?);
+            match parts.next() {
+                Some(s) if !s.is_empty() => {vt.push(line.index(s, self.uvs.len())?);},
+                _ => ()
+            }
+            match parts.next() {
+                Some(s) if !s.is_empty() => {vn.push(line.index(s, self.normals.len())?);},
+                _ => ()
+            }
+        }
+
+        let points = v.iter().map(|&i| self.positions[i]).collect::<Vec<_>>();
+        for tri in triangulate(&points) {
+            let pick = |l: &Vec<usize>| if l.len() == v.len() {
+                Some([l[tri[0]], l[tri[1]], l[tri[2]]])
+            } else {
+                None
+            };
+            self.faces.push(ObjFace {vertices: pick(&v).unwrap(), uvs: pick(&vt),
+                normals: pick(&vn), material});
+        }
+        Ok(())
+    }
+
+    // One mesh per material, faces without a material use `default`
+    pub fn to_objects(&self, default: &ObjMaterial) -> Vec<Object> {
+        let mut groups: Vec<(Option<usize>, Vec<&ObjFace>)> = Vec::new();
+        for f in &self.faces {
+            match groups.iter().position(|g| g.0 == f.material) {
+                Some(i) => {groups[i].1.push(f);},
+                None => {groups.push((f.material, vec![f]));}
+            }
+        }
+
+        groups.into_iter().map(|(material, faces)| {
+            let m = material.map_or(default, |i| &self.materials[i]);
+            let mut o = Object::new(m.surface_color, m.emission_color, m.reflection,
+                                    m.transparency, Box::new(self.build_mesh(&faces)));
+            o.bump = m.bump.clone();
+            o.material = m.material;
+            o.ior = m.ior;
+            match m.surface {
+                Some(ref s) => o.with_surface(s.clone()),
+                None => o
+            }
+        }).collect()
+    }
+
+    // OBJ indexes positions, normals and uvs separately, meshes need a single
+    // index per vertex
+    fn build_mesh(&self, faces: &[&ObjFace]) -> Mesh {
+        let has_normals = faces.iter().all(|f| f.normals.is_some());
+        let has_uvs = faces.iter().all(|f| f.uvs.is_some());
+
+        let mut vertices: HashMap<(usize, usize, usize), u32> = HashMap::new();
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut uvs = Vec::new();
+        let mut indices = Vec::with_capacity(faces.len());
+
+        for f in faces {
+            let mut tri = [0u32; 3];
+            for (k, index) in tri.iter_mut().enumerate() {
+                let vn = if has_normals {f.normals.unwrap()[k]} else {0};
+                let vt = if has_uvs {f.uvs.unwrap()[k]} else {0};
+                let key = (f.vertices[k], vt, vn);
+                *index = *vertices.entry(key).or_insert_with(|| {
+                    positions.push(self.positions[key.0]);
+                    if has_normals {
+                        normals.push(self.normals[vn]);
+                    }
+                    if has_uvs {
+                        uvs.push(self.uvs[vt]);
+                    }
+                    positions.len() as u32 - 1
+                });
+            }
+            indices.push(tri);
+        }
+
+        Mesh::new(positions, if has_normals {Some(normals)} else {None}, uvs, indices)
+    }
+}
+
+#[test]
+fn obj_parse_test() {
+    let src = "
+        v 0 0 0
+        v 1 0 0
+        v 1 1 0
+        v 0.5 0.2 0
+        v 0 1 0
+        vn 0 0 2
+        # concave pentagon
+        f 1//1 2//1 3//1 4//1 5//1
+        f -3 -2 -1
+    ";
+    let mesh = ObjMesh::parse(src, Path::new("")).unwrap();
+    assert_eq!(mesh.faces.len(), 4);
+    assert!(mesh.faces[..3].iter().all(|f| f.normals.is_some() && f.uvs.is_none()));
+    assert_eq!(mesh.faces[3].vertices, [2, 3, 4]);
+    assert_eq!(mesh.normals[0].z, 1.);
+
+    // The reflex vertex must not be clipped as an ear
+    let area = mesh.faces[..3].iter().map(|f| {
+        let p = f.vertices.iter().map(|&i| mesh.positions[i]).collect::<Vec<_>>();
+        (p[1] - p[0]).cross(p[2] - p[0]).len() * 0.5
+    }).sum::<f64>();
+    assert!((area - 0.6).abs() < 1e-9);
+
+    assert!(ObjMesh::parse("v 0 0 0\nv 1 0 0\nv 0 1 0\n", Path::new("")).is_err());
+}
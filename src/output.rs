@@ -0,0 +1,220 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use png::{Encoder, HasParameters, ColorType, BitDepth};
+use super::vec3::Vec3;
+use super::tonemap::ToneMapping;
+use super::error::{Error, Result};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Png,
+    // Radiance RGBE
+    Hdr,
+    Pfm,
+    // OpenEXR, uncompressed 32-bit float scanlines
+    Exr
+}
+
+impl Format {
+    // Chosen from the file extension, PNG being the default
+    pub fn from_filename(filename: &str) -> Self {
+        let ext = Path::new(filename).extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match ext.as_deref() {
+            Some("hdr") => Format::Hdr,
+            Some("pfm") => Format::Pfm,
+            Some("exr") => Format::Exr,
+            _ => Format::Png
+        }
+    }
+}
+
+// Tone mapping only applies to low dynamic range formats
+pub fn write_to_file(width: usize, height: usize, img: &[Vec3<f64>], filename: &str,
+                     tone_mapping: &ToneMapping) -> Result<()> {
+
+    let w: BufWriter<Box<dyn Write>> = match filename {
+        "-" => BufWriter::new(Box::new(io::stdout())),
+        f => BufWriter::new(Box::new(File::create(f).map_err(|e| Error::io(f, e))?))
+    };
+
+    let path = if filename == "-" {"<stdout>"} else {filename};
+    match Format::from_filename(filename) {
+        Format::Png => write_png(w, width, height, img, tone_mapping),
+        Format::Hdr => write_hdr(w, width, height, img).map_err(|e| Error::io(path, e)),
+        Format::Pfm => write_pfm(w, width, height, img).map_err(|e| Error::io(path, e)),
+        Format::Exr => write_exr(w, width, height, img).map_err(|e| Error::io(path, e))
+    }
+}
+
+pub fn to_rgb8(img: &[Vec3<f64>], tone_mapping: &ToneMapping) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(img.len() * 3);
+    for pix in img {
+        let pix = tone_mapping.apply(*pix);
+        bytes.push((pix.x * 255. + 0.5) as u8);
+        bytes.push((pix.y * 255. + 0.5) as u8);
+        bytes.push((pix.z * 255. + 0.5) as u8);
+    }
+    bytes
+}
+
+pub fn write_png<W: Write>(w: W, width: usize, height: usize, img: &[Vec3<f64>],
+                           tone_mapping: &ToneMapping) -> Result<()> {
+    let bytes = to_rgb8(img, tone_mapping);
+    let mut encoder = Encoder::new(w, width as u32, height as u32);
+    encoder.set(ColorType::RGB).set(BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| Error::Encoding(e.to_string()))?;
+    writer.write_image_data(&bytes).map_err(|e| Error::Encoding(e.to_string()))
+}
+
+// Shared exponent encoding, see Graphics Gems II p. 80
+fn rgbe(pix: &Vec3<f64>) -> [u8; 4] {
+    let v = pix.x.max(pix.y).max(pix.z);
+    if v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // Saturated past the largest exponent, infinite radiance included
+    let e = (v.log2().floor() as i32).min(126) + 1;
+    let scale = 256. / 2f64.powi(e);
+    [(pix.x.max(0.) * scale) as u8, (pix.y.max(0.) * scale) as u8,
+     (pix.z.max(0.) * scale) as u8, (e + 128) as u8]
+}
+
+pub fn write_hdr<W: Write>(mut w: W, width: usize, height: usize, img: &[Vec3<f64>]) ->
+    io::Result<()> {
+    write!(w, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+    // Flat scanlines, readers tell them apart from RLE ones
+    for pix in img {
+        w.write_all(&rgbe(pix))?;
+    }
+    w.flush()
+}
+
+pub fn write_pfm<W: Write>(mut w: W, width: usize, height: usize, img: &[Vec3<f64>]) ->
+    io::Result<()> {
+    // A negative scale means little endian
+    write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+    // Rows go bottom to top
+    for row in img.chunks(width).rev() {
+        for pix in row {
+            for c in &[pix.x, pix.y, pix.z] {
+                w.write_all(&(*c as f32).to_bits().to_le_bytes())?;
+            }
+        }
+    }
+    w.flush()
+}
+
+fn exr_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+pub fn write_exr<W: Write>(mut w: W, width: usize, height: usize, img: &[Vec3<f64>]) ->
+    io::Result<()> {
+    let i32s = |vals: &[i32]| vals.iter().flat_map(|v| v.to_le_bytes().to_vec())
+        .collect::<Vec<u8>>();
+
+    // Magic number and version 2, single part scanline file
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0];
+
+    // Channels must be sorted by name
+    let mut channels = Vec::new();
+    for name in &["B", "G", "R"] {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        // FLOAT pixels, linear flag and padding, x and y sampling
+        channels.extend(i32s(&[2]));
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        channels.extend(i32s(&[1, 1]));
+    }
+    channels.push(0);
+    let window = i32s(&[0, 0, width as i32 - 1, height as i32 - 1]);
+    let one = 1f32.to_bits().to_le_bytes();
+
+    exr_attribute(&mut header, "channels", "chlist", &channels);
+    exr_attribute(&mut header, "compression", "compression", &[0]);
+    exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(&mut header, "pixelAspectRatio", "float", &one);
+    exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(&mut header, "screenWindowWidth", "float", &one);
+    header.push(0);
+    w.write_all(&header)?;
+
+    // Offset table, one uncompressed scanline per chunk
+    let line_len = 8 + width * 3 * 4;
+    let data_start = header.len() + height * 8;
+    for y in 0..height {
+        w.write_all(&((data_start + y * line_len) as u64).to_le_bytes())?;
+    }
+
+    for (y, row) in img.chunks(width).enumerate() {
+        w.write_all(&i32s(&[y as i32, (width * 3 * 4) as i32]))?;
+        // B, G, R
+        for channel in (0..3).rev() {
+            for pix in row {
+                w.write_all(&(pix[channel] as f32).to_bits().to_le_bytes())?;
+            }
+        }
+    }
+    w.flush()
+}
+
+#[test]
+fn output_test() {
+    let img = vec![Vec3::new(1., 1., 1.), Vec3::new(0.5, 0., 1e-40),
+                   Vec3::new(1e-40, 0., 0.), Vec3::new(f64::INFINITY, 0., 0.)];
+    let le = |bytes: &[u8]| {
+        let mut b = [0; 4];
+        b.copy_from_slice(&bytes[..4]);
+        b
+    };
+
+    let mut hdr = Vec::new();
+    write_hdr(&mut hdr, 2, 2, &img).unwrap();
+    let header = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 2\n";
+    assert_eq!(&hdr[..header.len()], &header[..]);
+    assert_eq!(&hdr[header.len()..], &[128, 128, 128, 129, 128, 0, 0, 128,
+                                       0, 0, 0, 0, 255, 0, 0, 255][..]);
+
+    let mut pfm = Vec::new();
+    write_pfm(&mut pfm, 2, 2, &img).unwrap();
+    let header = b"PF\n2 2\n-1.0\n";
+    assert_eq!(&pfm[..header.len()], &header[..]);
+    let floats: Vec<f32> = pfm[header.len()..].chunks(4)
+        .map(|b| f32::from_bits(u32::from_le_bytes(le(b)))).collect();
+    assert_eq!(floats.len(), 12);
+    // Bottom row first
+    assert_eq!(&floats[..3], &[1e-40, 0., 0.]);
+    assert_eq!(&floats[6..9], &[1., 1., 1.]);
+
+    let mut exr = Vec::new();
+    write_exr(&mut exr, 2, 2, &img).unwrap();
+    assert_eq!(&exr[..8], &[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+    let chlist = b"channels\0chlist\0";
+    let at = exr.windows(chlist.len()).position(|w| w == &chlist[..]).unwrap() + chlist.len();
+    let names: Vec<u8> = (0..3).map(|i| exr[at + 4 + i * 18]).collect();
+    assert_eq!(names, b"BGR".to_vec());
+    // Two offsets past the header, each scanline holding its y, size and
+    // 2 pixels of 3 floats
+    let line_len = 8 + 2 * 3 * 4;
+    let header_len = exr.len() - 2 * 8 - 2 * line_len;
+    assert_eq!(exr[header_len - 1], 0);
+    for y in 0..2 {
+        let entry = header_len + y * 8;
+        let mut offset = [0; 8];
+        offset.copy_from_slice(&exr[entry..entry + 8]);
+        let offset = u64::from_le_bytes(offset) as usize;
+        assert_eq!(offset, header_len + 2 * 8 + y * line_len);
+        assert_eq!(le(&exr[offset..]), (y as i32).to_le_bytes());
+        assert_eq!(le(&exr[offset + 4..]), 24i32.to_le_bytes());
+    }
+}
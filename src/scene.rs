@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+use std::str::FromStr;
+use super::vec3::Vec3;
+use super::camera::Camera;
+use super::solids::Object;
+use super::surface::{Surface, Bump};
+use super::material::{Material, Fresnel};
+use super::integrator::emitters;
+use super::light::Light;
+use super::error::{Error, ParseError, Result, column_of, fields, field_count, rest, parse_field,
+                   parse_number};
+
+// Radiance of rays escaping the scene
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Background {
+    Constant(Vec3<f64>),
+    // `color` scaled by the squared cosine between the ray and `direction`
+    Gradient {color: Vec3<f64>, direction: Vec3<f64>}
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Gradient {color: Vec3::new(0.1, 0.3, 0.5), direction: Vec3::new(0., 0., -1.)}
+    }
+}
+
+impl Background {
+    pub fn eval(&self, dir: Vec3<f64>) -> Vec3<f64> {
+        match *self {
+            Background::Constant(c) => c,
+            Background::Gradient {color, direction} => color * dir.dot(&direction).powi(2)
+        }
+    }
+}
+
+// color[, direction]
+impl FromStr for Background {
+    type Err = ParseError;
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        let vals = fields(s);
+        let color = parse_field::<Vec3<f64>>(s, vals[0])? * (1. / 255.);
+        match vals.len() {
+            1 => Ok(Background::Constant(color)),
+            2 => {
+                let mut direction: Vec3<f64> = parse_field(s, vals[1])?;
+                direction.normalize();
+                Ok(Background::Gradient {color, direction})
+            },
+            _ => Err(field_count(s, 2, "background"))
+        }
+    }
+}
+
+// Light transport parameters shared by the integrators
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Settings {
+    // Recursion limit of the Whitted integrator
+    pub max_depth: usize,
+    // Path length limit of the path tracer
+    pub max_bounces: usize,
+    // Index of refraction of transparent objects without their own
+    pub ior: f64,
+    pub fresnel: Fresnel,
+    // Offset of secondary ray origins, avoiding self intersections
+    pub bias: f64,
+    // Shadow rays per area light in the Whitted integrator
+    pub shadow_samples: usize
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {max_depth: 5, max_bounces: 64, ior: 1.1, fresnel: Fresnel::Exact, bias: 1e-4,
+                  shadow_samples: 1}
+    }
+}
+
+// Surfaces declared by name, and the properties given to the objects using
+// them
+#[derive(Default)]
+pub struct Declarations {
+    pub surfaces: HashMap<String, Surface>,
+    pub bumps: HashMap<String, Bump>,
+    pub materials: HashMap<String, Material>,
+    pub iors: HashMap<String, f64>
+}
+
+pub struct Scene {
+    pub objects: Vec<Object>,
+    // Explicit lights and area lights of the emissive objects, the latter
+    // kept up to date by `add`
+    pub lights: Vec<Light>,
+    pub camera: Camera,
+    pub background: Background,
+    pub settings: Settings
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Scene::new(Vec::new(), Camera::default())
+    }
+}
+
+impl Scene {
+    pub fn new(objects: Vec<Object>, camera: Camera) -> Self {
+        let lights = emitters(&objects);
+        Scene {objects, lights, camera, background: Background::default(),
+            settings: Settings::default()}
+    }
+
+    pub fn add(&mut self, object: Object) {
+        self.objects.push(object);
+        let i = self.objects.len() - 1;
+        if !emitters(&self.objects[i..]).is_empty() {
+            self.lights.push(Light::Area {object: i});
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<Self> {
+        let mut file_str = String::new();
+        if path == "-" {
+            ::std::io::stdin().read_to_string(&mut file_str)
+                .map_err(|e| Error::io("stdin", e))?;
+        } else {
+            File::open(path).and_then(|mut f| f.read_to_string(&mut file_str))
+                .map_err(|e| Error::io(path, e))?;
+        }
+
+        // OBJ files are relative to the scene file
+        let dir = match path {
+            "-" => Path::new(""),
+            p => Path::new(p).parent().unwrap_or_else(|| Path::new(""))
+        };
+        Self::parse(&file_str, dir).map_err(|e| match e {
+            Error::Parse(ref p) if p.file.is_none() => Error::Parse(p.clone().in_file(path)),
+            e => e
+        })
+    }
+
+    // `dir` is where OBJ files are looked up
+    pub fn parse(src: &str, dir: &Path) -> Result<Self> {
+        let mut scene = Scene::default();
+        let mut named = Declarations::default();
+        for (i, line) in src.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with("//") {
+                continue;
+            }
+            scene.parse_line(line, &mut named, dir).map_err(|e| match e {
+                Error::Parse(ref p) if p.line == 0 => Error::Parse(p.clone().at(i + 1, 1)),
+                e => e
+            })?;
+        }
+        Ok(scene)
+    }
+
+    // `named` holds the surfaces and their properties declared so far
+    fn parse_line(&mut self, line: &str, named: &mut Declarations, dir: &Path) -> Result<()> {
+        let tokens = fields(line);
+        let value = rest(line, &tokens, 1);
+        let count = |n| if tokens.len() == n + 1 {Ok(())} else {
+            Err(field_count(value, n, tokens[0]).offset(column_of(line, value)))
+        };
+        // Properties applying to the objects using a surface name after the
+        // keyword
+        let surface_name = |surfaces: &HashMap<String, Surface>| {
+            let name = tokens.get(1).cloned().unwrap_or("");
+            if !surfaces.contains_key(name) {
+                return Err(ParseError::new("Unknown surface name", name)
+                           .at_column(column_of(line, name)));
+            }
+            Ok(name.to_string())
+        };
+        match tokens[0] {
+            "camera" => {self.camera = parse_field(line, value)?;},
+            "background" => {self.background = parse_field(line, value)?;},
+            "light" => {self.lights.push(parse_field(line, value)?);},
+            "surface" => {
+                // surface, name, definition
+                let name = tokens.get(1).cloned().unwrap_or("");
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    return Err(ParseError::new("Invalid surface name", name)
+                               .at_column(column_of(line, name)).into());
+                }
+                let def = rest(line, &tokens, 2);
+                named.surfaces.insert(name.to_string(), parse_field(line, def)?);
+            },
+            "bump" => {
+                // bump, surface name, definition
+                let name = surface_name(&named.surfaces)?;
+                let def = rest(line, &tokens, 2);
+                named.bumps.insert(name, parse_field(line, def)?);
+            },
+            "material" => {
+                // material, surface name, definition
+                let name = surface_name(&named.surfaces)?;
+                let def = rest(line, &tokens, 2);
+                named.materials.insert(name, parse_field(line, def)?);
+            },
+            "max_depth" => {
+                count(1)?;
+                self.settings.max_depth = parse_number(line, tokens[1], "depth")?;
+            },
+            "max_bounces" => {
+                count(1)?;
+                self.settings.max_bounces = parse_number(line, tokens[1], "bounce count")?;
+            },
+            "ior" => {
+                // ior[, surface name], index
+                let name = if tokens.len() == 3 {Some(surface_name(&named.surfaces)?)} else {
+                    count(1)?;
+                    None
+                };
+                let t = tokens[tokens.len() - 1];
+                let ior: f64 = parse_number(line, t, "index of refraction")?;
+                if ior <= 0. {
+                    return Err(ParseError::new("Expected a positive index of refraction", t)
+                               .at_column(column_of(line, t)).into());
+                }
+                match name {
+                    Some(name) => {named.iors.insert(name, ior);},
+                    None => {self.settings.ior = ior;}
+                }
+            },
+            "fresnel" => {
+                count(1)?;
+                self.settings.fresnel = parse_field(line, tokens[1])?;
+            },
+            "bias" => {
+                count(1)?;
+                self.settings.bias = parse_number(line, tokens[1], "bias")?;
+            },
+            "shadow_samples" => {
+                count(1)?;
+                let n: usize = parse_number(line, tokens[1], "shadow sample count")?;
+                if n == 0 {
+                    return Err(ParseError::new("Expected at least one shadow sample", tokens[1])
+                               .at_column(column_of(line, tokens[1])).into());
+                }
+                self.settings.shadow_samples = n;
+            },
+            _ => {
+                for o in Object::from_line(line, named, dir)? {
+                    self.add(o);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Scene {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self> {
+        Scene::parse(s, Path::new(""))
+    }
+}
+
+#[test]
+fn scene_error_location_test() {
+    let src = "// comment\n\
+               255 0 0, 0 0 0, 1, .5, sphere, 0 0 -20, 4\n\
+               255 0 0, 0 0 0, 1, x, sphere, 0 0 -20, 4\n";
+    match Scene::from_str(src) {
+        Err(Error::Parse(e)) => {
+            assert_eq!((e.line, e.column), (3, 20));
+            assert_eq!(e.token, "x");
+        },
+        _ => panic!("expected a parse error")
+    }
+
+    match Scene::from_str("shadow_samples, 0\n") {
+        Err(Error::Parse(e)) => {assert_eq!((e.line, e.column), (1, 17));},
+        _ => panic!("expected a parse error")
+    }
+}
+
+#[test]
+fn scene_settings_test() {
+    let scene = Scene::from_str("ior, 1.5\nbackground, 255 255 255\n\
+                                 0 0 0, 255 255 255, 0, 0, sphere, 0 0 0, 1\n").unwrap();
+    assert_eq!(scene.settings.ior, 1.5);
+    assert_eq!(scene.background, Background::Constant(Vec3::new(1., 1., 1.)));
+    assert_eq!(scene.lights, vec![Light::Area {object: 0}]);
+}
+
+#[test]
+fn scene_surface_test() {
+    let scene = Scene::from_str("surface, moss, noise, 7, 4, 255 255 255\n\
+                                 moss, 0 0 0, 0, 0, sphere, 0 0 0, 1\n").unwrap();
+    match scene.objects[0].surface {
+        Surface::Noise(ref n) => {assert_eq!(n.scale, 4.);},
+        _ => panic!("expected a noise surface")
+    }
+}
+
+#[test]
+fn scene_material_test() {
+    let scene = Scene::from_str("surface, glass, solid, 255 255 255\n\
+                                 material, glass, dielectric, 1.5, 0\n\
+                                 glass, 0 0 0, 0, 0, sphere, 0 0 0, 1\n").unwrap();
+    assert_eq!(scene.objects[0].material, Some(Material::Dielectric {ior: 1.5, roughness: 0.}));
+}
+
+#[test]
+fn scene_ior_test() {
+    let scene = Scene::from_str("fresnel, schlick\nsurface, water, solid, 200 220 255\n\
+                                 ior, water, 1.33\n\
+                                 water, 0 0 0, 0, 1, sphere, 0 0 0, 1\n\
+                                 255 255 255, 0 0 0, 0, 1, sphere, 0 0 0, 1\n").unwrap();
+    assert_eq!(scene.settings.fresnel, Fresnel::Schlick);
+    assert_eq!((scene.objects[0].ior, scene.objects[1].ior), (Some(1.33), None));
+    match Scene::from_str("ior, ice, 1.31\n") {
+        Err(Error::Parse(e)) => {assert_eq!((e.column, e.token.as_str()), (6, "ice"));},
+        _ => panic!("expected a parse error")
+    }
+}
+
+#[test]
+fn scene_obj_test() {
+    use std::fs;
+
+    let dir = ::std::env::temp_dir().join(format!("raytracer_scene_test_{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("mesh.obj"), "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+    let path = dir.join("scene.rtcr");
+    fs::write(&path, "surface, glass, solid, 255 255 255\n\
+                      material, glass, dielectric, 1.5, 0\n\
+                      glass, 0 0 0, 0, 0, obj, mesh.obj\n").unwrap();
+    // Clean up before checking the result so a failed load leaves nothing behind
+    let scene = Scene::from_file(path.to_str().unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+    // Found next to the scene file, with the line's material
+    let scene = scene.unwrap();
+    assert_eq!(scene.objects.len(), 1);
+    assert_eq!(scene.objects[0].material, Some(Material::Dielectric {ior: 1.5, roughness: 0.}));
+}
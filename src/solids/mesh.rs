@@ -0,0 +1,171 @@
+use std::cmp::Ordering;
+use super::{Vec3, Solid, Hit, Aabb, Bvh};
+use super::triangle::intersect_triangle;
+
+pub struct Mesh {
+    pub positions: Vec<Vec3<f64>>,
+    // Per vertex, same length as `positions`
+    pub normals: Vec<Vec3<f64>>,
+    // Per vertex, empty if the mesh has no texture coordinates
+    pub uvs: Vec<(f64, f64)>,
+    pub indices: Vec<[u32; 3]>,
+    // Cumulative face areas, for surface sampling
+    areas: Vec<f64>,
+    bvh: Bvh
+}
+
+impl Mesh {
+    // Without normals, smooth ones are computed from the faces
+    pub fn new(positions: Vec<Vec3<f64>>, normals: Option<Vec<Vec3<f64>>>,
+               uvs: Vec<(f64, f64)>, indices: Vec<[u32; 3]>) -> Self {
+        let boxes = indices.iter().map(|tri| Aabb::from_points(&[
+            positions[tri[0] as usize],
+            positions[tri[1] as usize],
+            positions[tri[2] as usize]
+        ])).collect::<Vec<_>>();
+        let bvh = Bvh::new(&boxes);
+        let normals = match normals {
+            Some(n) => n,
+            None => vertex_normals(&positions, &indices)
+        };
+
+        let mut total = 0.;
+        let areas = indices.iter().map(|tri| {
+            let (p0, p1, p2) = (positions[tri[0] as usize], positions[tri[1] as usize],
+                                positions[tri[2] as usize]);
+            // Faces with NaN or infinite vertices are never sampled
+            let area = (p1 - p0).cross(p2 - p0).len() * 0.5;
+            if area.is_finite() {
+                total += area;
+            }
+            total
+        }).collect();
+
+        Mesh {positions, normals, uvs, indices, areas, bvh}
+    }
+
+    fn vertices(&self, tri: usize) -> (Vec3<f64>, Vec3<f64>, Vec3<f64>) {
+        let [a, b, c] = self.indices[tri];
+        (self.positions[a as usize], self.positions[b as usize], self.positions[c as usize])
+    }
+
+    fn intersect_face(&self, tri: usize, org: Vec3<f64>, dir: Vec3<f64>)
+        -> Option<(f64, f64, f64)> {
+        let (p0, p1, p2) = self.vertices(tri);
+        intersect_triangle(org, dir, p0, p1 - p0, p2 - p0)
+    }
+}
+
+// Area weighted average of the adjacent face normals
+fn vertex_normals(positions: &[Vec3<f64>], indices: &[[u32; 3]]) -> Vec<Vec3<f64>> {
+    let mut normals = vec![Vec3::default(); positions.len()];
+    for tri in indices {
+        let (p0, p1, p2) = (positions[tri[0] as usize], positions[tri[1] as usize],
+                            positions[tri[2] as usize]);
+        let n = (p1 - p0).cross(p2 - p0);
+        for &i in tri {
+            normals[i as usize] = normals[i as usize] + n;
+        }
+    }
+    for n in normals.iter_mut() {
+        n.normalize();
+    }
+    normals
+}
+
+impl Solid for Mesh {
+    // Smooth shading normals and interpolated texture coordinates, the face's
+    // barycentric ones without them
+    fn intersect(&self, org: Vec3<f64>, dir: Vec3<f64>, t_min: f64, t_max: f64) -> Option<Hit> {
+        let (tri, (t, w, z)) = self.bvh.intersect(org, dir, t_max, |tri, t_max| {
+            match self.intersect_face(tri, org, dir) {
+                Some(h @ (t, _, _)) if t >= t_min && t <= t_max => Some((t, h)),
+                _ => None
+            }
+        })?;
+
+        let (p0, p1, p2) = self.vertices(tri);
+        let mut normal = (p1 - p0).cross(p2 - p0);
+        normal.normalize();
+        let [a, b, c] = self.indices[tri];
+        let (a, b, c) = (a as usize, b as usize, c as usize);
+        let s = 1. - w - z;
+        let uv = if self.uvs.is_empty() {(w, z)} else {
+            let (ta, tb, tc) = (self.uvs[a], self.uvs[b], self.uvs[c]);
+            (ta.0 * s + tb.0 * w + tc.0 * z, ta.1 * s + tb.1 * w + tc.1 * z)
+        };
+        let mut shading = self.normals[a] * s + self.normals[b] * w + self.normals[c] * z;
+        shading.normalize();
+
+        Some(Hit::new(org, dir, t, normal, uv, tri).with_shading_normal(shading))
+    }
+
+    // Solved from the face's texture coordinates, its edges without them
+    fn tangents_at(&self, hit: &Hit) -> (Vec3<f64>, Vec3<f64>) {
+        let tri = hit.primitive;
+        let (p0, p1, p2) = self.vertices(tri);
+        let (e1, e2) = (p1 - p0, p2 - p0);
+        if self.uvs.is_empty() {
+            return (e1, e2);
+        }
+        let [a, b, c] = self.indices[tri];
+        let (ta, tb, tc) = (self.uvs[a as usize], self.uvs[b as usize], self.uvs[c as usize]);
+        let (du1, dv1) = (tb.0 - ta.0, tb.1 - ta.1);
+        let (du2, dv2) = (tc.0 - ta.0, tc.1 - ta.1);
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < 1e-12 {
+            return (e1, e2);
+        }
+        let inv = 1. / det;
+        ((e1 * dv2 - e2 * dv1) * inv, (e2 * du1 - e1 * du2) * inv)
+    }
+
+    fn position(&self) -> Vec3<f64> {self.bvh.bounds().centroid()}
+
+    fn area(&self) -> f64 {
+        *self.areas.last().unwrap_or(&0.)
+    }
+
+    fn sample_surface(&self, u: f64, v: f64) -> (Vec3<f64>, Vec3<f64>) {
+        if self.indices.is_empty() {
+            return (self.position(), Vec3::default());
+        }
+        // Pick a face proportionally to its area and reuse `u`
+        let target = u * self.area();
+        let tri = match self.areas.binary_search_by(|a| a.partial_cmp(&target)
+                                                      .unwrap_or(Ordering::Less)) {
+            Ok(i) | Err(i) => i.min(self.indices.len() - 1)
+        };
+        let lo = if tri == 0 {0.} else {self.areas[tri - 1]};
+        let su = if self.areas[tri] > lo {
+            ((target - lo) / (self.areas[tri] - lo)).sqrt()
+        } else {
+            0.
+        };
+
+        let (p0, p1, p2) = self.vertices(tri);
+        let p = p0 * (1. - su) + p1 * (su * (1. - v)) + p2 * (su * v);
+        let mut n = (p1 - p0).cross(p2 - p0);
+        n.normalize();
+        (p, n)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+}
+
+#[test]
+fn mesh_smooth_normal_test() {
+    let positions = vec![Vec3::new(0., 0., 0.), Vec3::new(1., 0., 0.), Vec3::new(0., 1., 0.)];
+    let mut tilted = Vec3::new(1., 0., 1.);
+    tilted.normalize();
+    let normals = vec![Vec3::new(0., 0., 1.), tilted, Vec3::new(0., 0., 1.)];
+    let mesh = Mesh::new(positions, Some(normals), Vec::new(), vec![[0, 1, 2]]);
+
+    let hit = mesh.intersect(Vec3::new(0.5, 0.25, 1.), Vec3::new(0., 0., -1.), 0., f64::INFINITY)
+        .unwrap();
+    assert!((hit.t - 1.).abs() < 1e-9 && hit.front_face);
+    let n = hit.shading_normal;
+    assert!(n.x > 0.3 && n.x < tilted.x && n.z > 0.);
+}
@@ -1,124 +1,261 @@
 pub mod sphere;
 pub mod triangle;
 pub mod rectangle;
+pub mod mesh;
 
-//use std::f64;
 use std::str::FromStr;
-use std::fs::File;
-use std::io::prelude::*;
-use super::vec3::Vec3;
+use std::path::Path;
+use super::vec3::{Vec3, basis};
+use super::surface::{Surface, TexCoords, Bump};
+use super::material::Material;
+use super::bvh::{Aabb, Bvh};
+use super::differential::Footprint;
+use super::obj::{ObjMesh, ObjMaterial};
+use super::scene::{Scene, Declarations};
+use super::error::{ParseError, Result, column_of, fields, field_count, rest, parse_field,
+                   parse_number};
 use std::marker::Sync;
 
 pub struct Object {
     pub pos: Vec3<f64>,
     pub emission_color: Vec3<f64>,
-    pub surface_color: Vec3<f64>,
+    pub surface: Surface,
+    pub bump: Option<Bump>,
+    // Scattering model, in place of `reflection` and `transparency` when set
+    pub material: Option<Material>,
     pub transparency: f64,
+    // Index of refraction when transparent, the scene's by default
+    pub ior: Option<f64>,
     pub reflection: f64,
-    pub solid: Box<Solid + Sync>
+    pub solid: Box<dyn Solid + Sync>
 }
 
-pub fn html_color_to_vec3(s: &str) -> Result<Vec3<f64>, ()> {
-    if s.len() < 7 || !s.starts_with('#') {
-        return Err(());
+pub fn html_color_to_vec3(s: &str) -> ::std::result::Result<Vec3<f64>, ParseError> {
+    let invalid = || ParseError::new("Invalid HTML color", s);
+    if s.len() < 7 || !s.starts_with('#') || !s.is_char_boundary(7) {
+        return Err(invalid());
     }
 
-    let r = (u8::from_str_radix(&s[1..3], 16).or_else(|_| Err(()))? as f64) / 255.;
-    let g = (u8::from_str_radix(&s[3..5], 16).or_else(|_| Err(()))? as f64) / 255.;
-    let b = (u8::from_str_radix(&s[5..7], 16).or_else(|_| Err(()))? as f64) / 255.;
+    let r = (u8::from_str_radix(&s[1..3], 16).map_err(|_| invalid())? as f64) / 255.;
+    let g = (u8::from_str_radix(&s[3..5], 16).map_err(|_| invalid())? as f64) / 255.;
+    let b = (u8::from_str_radix(&s[5..7], 16).map_err(|_| invalid())? as f64) / 255.;
 
     Ok(Vec3::new(r, g, b))
 }
 
-fn to_single_whitespace(s: &str) -> String {
-    let mut out: String = String::with_capacity(s.len());
-    let chars = s.chars().collect::<Vec<char>>();
-    let mut i = 0;
-    while i < s.len() {
-        match chars[i] {
-            ' ' | '\t' => {
-                i += 1;
-                while i < s.len() && (chars[i] == ' ' || chars[i] == '\t') {
-                    i += 1;
-                }
-                out.push(' ');
-            }
-            c => {i += 1; out.push(c);}
-        }
-    }
-    out
-}
-
 impl Object {
     pub fn new(surface_color: Vec3<f64>, emission_color: Vec3<f64>,
-               reflection: f64, transparency: f64, solid: Box<Solid + Sync>) -> Self {
-        Object {pos: solid.position(), emission_color, surface_color,
-            transparency, reflection, solid}
-    }
-
-    pub fn from_file(path: &str) -> Result<Vec<Object>, String> {
-        let mut file_str = String::new();
-        if path == "-" {
-            ::std::io::stdin().read_to_string(&mut file_str).or_else(|_| 
-                Err(format!("Error reading from stdin.")))?;
-        } else {
-            let mut file = File::open(path).or_else(|_e|
-                Err(format!("Could not read file {}", path)))?;
-            file.read_to_string(&mut file_str).or_else(|_e|
-                Err(format!("Could not read file {}", path)))?;
-        }
+               reflection: f64, transparency: f64, solid: Box<dyn Solid + Sync>) -> Self {
+        Object {pos: solid.position(), emission_color, surface: Surface::Solid(surface_color),
+            bump: None, material: None, transparency, ior: None, reflection, solid}
+    }
 
-        Self::vec_from_str(&file_str)
+    pub fn with_surface(mut self, surface: Surface) -> Self {
+        self.surface = surface;
+        self
     }
 
-    pub fn vec_from_str(file_str: &str) -> Result<Vec<Object>, String> {
-        let file_str = &to_single_whitespace(file_str);
-        let mut r = Vec::<Object>::new();
-        
-        for (i, line) in file_str.split('\n').enumerate() {
-            let line = line.trim();
-            if line.len() == 0 || line.starts_with("//") {
-                continue;
-            }
-            let mut tokens = line.split(", ").collect::<Vec<&str>>();
-            if tokens.len() < 5 {
-                return Err(format!("Invalid line: {}", i + 1));
-            }
+    pub fn with_bump(mut self, bump: Bump) -> Self {
+        self.bump = Some(bump);
+        self
+    }
 
-            let surface_color = Vec3::from_str(tokens[0])
-                .or_else(|_| Err(format!("Invalid reflection value: line {}", i + 1)))? * (1. / 255.);
-            let emission_color = Vec3::from_str(tokens[1])
-                .or_else(|_| Err(format!("Invalid reflection value: line {}", i + 1)))? * (1. / 255.);
+    pub fn with_ior(mut self, ior: f64) -> Self {
+        self.ior = Some(ior);
+        self
+    }
 
-            let reflection = f64::from_str(&tokens[2])
-                .or_else(|_| Err(format!("Invalid reflection value: line {}", i + 1)))?;
-            let transparency = f64::from_str(&tokens[3])
-                .or_else(|_| Err(format!("Invalid transparency value: line {}", i + 1)))?;
+    pub fn with_material(mut self, material: Material) -> Self {
+        self.material = Some(material);
+        self
+    }
 
-            let solid: Box<Solid + Sync> = match tokens[4] {
-                "sphere" => Box::new(sphere::Sphere::from_str(
-                        &tokens[5..].join(", ")).or_else(|_|
-                        Err(format!("Invalid sphere definition: line {}", i + 1)))?),
+    pub fn color_at(&self, hit: &Hit) -> Vec3<f64> {
+        self.surface.eval(&self.texcoords(hit, None))
+    }
+
+    // Lookup at `hit`, filtered over `footprint` when given
+    pub fn texcoords(&self, hit: &Hit, footprint: Option<&Footprint>) -> TexCoords {
+        let duv = match footprint {
+            Some(f) => {
+                let (dpdu, dpdv) = self.solid.tangents_at(hit);
+                f.uv_derivatives(hit, dpdu, dpdv)
+            },
+            None => [(0., 0.); 2]
+        };
+        TexCoords {uv: hit.uv, point: hit.point, local: hit.point - self.pos, duv}
+    }
 
-                "triangle" => Box::new(triangle::Triangle::from_str(
-                        &tokens[5..].join(", ")).or_else(|_|
-                        Err(format!("Invalid triangle definition: line {}", i + 1)))?),
+    // Shading normal at `hit`, perturbed by the bump map if any
+    pub fn shading_normal(&self, hit: &Hit, tc: &TexCoords) -> Vec3<f64> {
+        match self.bump {
+            Some(ref b) => {
+                let (dpdu, dpdv) = self.solid.tangents_at(hit);
+                b.apply(tc, hit.shading_normal, dpdu, dpdv)
+            },
+            None => hit.shading_normal
+        }
+    }
 
-                "rectangle" => Box::new(rectangle::Rectangle::from_str(
-                        &tokens[5..].join(", ")).or_else(|_|
-                        Err(format!("Invalid rectangle definition: line {}", i + 1)))?),
-                _ => {return Err("Invalid input file.".to_string());}
-            };
+    pub fn from_file(path: &str) -> Result<Vec<Object>> {
+        Scene::from_file(path).map(|scene| scene.objects)
+    }
 
-            r.push(Object::new(surface_color, emission_color, reflection, transparency, solid));
+    pub fn vec_from_str(file_str: &str) -> Result<Vec<Object>> {
+        Scene::from_str(file_str).map(|scene| scene.objects)
+    }
+
+    // One scene file line, error columns being relative to it. The surface
+    // color may name one of the declared surfaces, whose properties the
+    // objects get. Meshes expand to one object per material, OBJ files being
+    // looked up in `dir`.
+    pub fn from_line(line: &str, named: &Declarations, dir: &Path) -> Result<Vec<Object>> {
+        let tokens = fields(line);
+        if tokens.len() < 5 {
+            let trimmed = line.trim();
+            return Err(ParseError::new("Expected at least 5 fields", trimmed)
+                       .at_column(column_of(line, trimmed)).into());
         }
 
-        Ok(r)
+        let surface = match named.surfaces.get(tokens[0]) {
+            Some(s) => s.clone(),
+            None => Surface::Solid(parse_field::<Vec3<f64>>(line, tokens[0])
+                                   .map_err(|e| e.context("surface color"))? * (1. / 255.))
+        };
+        let surface_color = match surface {
+            Surface::Solid(c) => c,
+            _ => Vec3::new(1., 1., 1.)
+        };
+        let emission_color = parse_field::<Vec3<f64>>(line, tokens[1])
+            .map_err(|e| e.context("emission color"))? * (1. / 255.);
+
+        let reflection = parse_number(line, tokens[2], "reflection value")?;
+        let transparency = parse_number(line, tokens[3], "transparency value")?;
+
+        let bump = named.bumps.get(tokens[0]).cloned();
+        let material = named.materials.get(tokens[0]).cloned();
+        let ior = named.iors.get(tokens[0]).cloned();
+
+        let def = rest(line, &tokens, 5);
+        // The line's properties are used for faces without a material
+        if tokens[4] == "obj" {
+            let mesh = ObjMesh::from_file(&dir.join(def.trim()).display().to_string())?;
+            let mut default = ObjMaterial::new("", surface_color, emission_color,
+                                               reflection, transparency);
+            default.surface = Some(surface);
+            default.bump = bump;
+            default.material = material;
+            default.ior = ior;
+            return Ok(mesh.to_objects(&default));
+        }
+
+        let solid: Box<dyn Solid + Sync> = match tokens[4] {
+            "sphere" => Box::new(parse_field::<sphere::Sphere>(line, def)?),
+            "triangle" => Box::new(parse_field::<triangle::Triangle>(line, def)?),
+            "rectangle" => Box::new(parse_field::<rectangle::Rectangle>(line, def)?),
+            t => {
+                return Err(ParseError::new("Unknown solid type", t)
+                           .at_column(column_of(line, t)).into());
+            }
+        };
+
+        let mut o = Object::new(surface_color, emission_color, reflection, transparency, solid)
+            .with_surface(surface);
+        o.bump = bump;
+        o.material = material;
+        o.ior = ior;
+        Ok(vec![o])
+    }
+}
+
+pub struct Hit {
+    pub t: f64,
+    pub point: Vec3<f64>,
+    // Geometric normal, facing the ray origin
+    pub normal: Vec3<f64>,
+    // Interpolated normal, on the same side as `normal`
+    pub shading_normal: Vec3<f64>,
+    // Surface coordinates, for texturing
+    pub uv: (f64, f64),
+    // Whether the ray arrived on the outer side of the surface
+    pub front_face: bool,
+    // Face of solids made of several, 0 otherwise
+    pub primitive: usize
+}
+
+impl Hit {
+    // `outward` is the unit geometric normal, flipped here to face the ray
+    pub fn new(org: Vec3<f64>, dir: Vec3<f64>, t: f64, outward: Vec3<f64>, uv: (f64, f64),
+               primitive: usize) -> Self {
+        let front_face = outward.dot(&dir) <= 0.;
+        let normal = if front_face {outward} else {-outward};
+        Hit {t, point: org + dir * t, normal, shading_normal: normal, uv, front_face, primitive}
+    }
+
+    pub fn with_shading_normal(mut self, n: Vec3<f64>) -> Self {
+        self.shading_normal = if n.dot(&self.normal) < 0. {-n} else {n};
+        self
     }
 }
 
+// Closest object hit by the ray within [t_min, t_max]
+pub fn closest_hit(objects: &[Object], bvh: &Bvh, org: Vec3<f64>, dir: Vec3<f64>, t_min: f64,
+                   t_max: f64) -> Option<(usize, Hit)> {
+    bvh.intersect(org, dir, t_max, |i, t_max| {
+        objects[i].solid.intersect(org, dir, t_min, t_max).map(|h| (h.t, h))
+    })
+}
+
 pub trait Solid {
-    fn intersect(&self, origin: Vec3<f64>, direction: Vec3<f64>) -> Option<f64>;
-    fn normal_at(&self, hit: Vec3<f64>, dir: Vec3<f64>) -> Vec3<f64>;
+    // Closest hit within [t_min, t_max], `dir` being normalized
+    fn intersect(&self, org: Vec3<f64>, dir: Vec3<f64>, t_min: f64, t_max: f64) -> Option<Hit>;
     fn position(&self) -> Vec3<f64>;
+    // Partial derivatives of the position along u and v, unnormalized
+    fn tangents_at(&self, hit: &Hit) -> (Vec3<f64>, Vec3<f64>);
+    fn bounding_box(&self) -> Aabb;
+    fn area(&self) -> f64;
+    // Uniformly distributed point on the surface and its normal, from two
+    // random numbers in [0, 1)
+    fn sample_surface(&self, u: f64, v: f64) -> (Vec3<f64>, Vec3<f64>);
+
+    // Point on the surface as seen from `p`, its normal, and its probability
+    // density per unit solid angle at `p`. Uniform over the area by default.
+    fn sample_from(&self, p: Vec3<f64>, u: f64, v: f64) -> Option<(Vec3<f64>, Vec3<f64>, f64)> {
+        sample_area_from(self, p, u, v)
+    }
+}
+
+// Uniform area sampling of `solid`, with the density converted to solid angle
+// at `p`
+pub fn sample_area_from<S: Solid + ?Sized>(solid: &S, p: Vec3<f64>, u: f64, v: f64)
+    -> Option<(Vec3<f64>, Vec3<f64>, f64)> {
+    let (q, n) = solid.sample_surface(u, v);
+    let mut wi = q - p;
+    let dist2 = wi.len_sqr();
+    wi.normalize();
+    let cos = n.dot(&wi).abs();
+    if cos <= 0. || dist2 == 0. {
+        return None;
+    }
+    Some((q, n, dist2 / (cos * solid.area())))
+}
+
+#[test]
+fn intersect_interval_test() {
+    let org = Vec3::default();
+    let dir = Vec3::new(0., 0., -1.);
+
+    // Roots at 4 and 6, the far one hit from inside
+    let s = sphere::Sphere::new(Vec3::new(0., 0., -5.), 1.);
+    assert_eq!(s.intersect(org, dir, 0., f64::INFINITY).map(|h| h.t), Some(4.));
+    let far = s.intersect(org, dir, 5., f64::INFINITY).unwrap();
+    assert_eq!((far.t, far.front_face), (6., false));
+    assert!(s.intersect(org, dir, 0., 3.).is_none());
+    assert!(s.intersect(org, dir, 7., f64::INFINITY).is_none());
+
+    let t = triangle::Triangle::new(Vec3::new(-1., -1., -2.), Vec3::new(1., -1., -2.),
+                                    Vec3::new(0., 1., -2.));
+    assert_eq!(t.intersect(org, dir, 1., 3.).map(|h| h.t), Some(2.));
+    assert!(t.intersect(org, dir, 0., 1.5).is_none());
+    assert!(t.intersect(org, dir, 2.5, f64::INFINITY).is_none());
 }
@@ -1,6 +1,7 @@
 use std::str::FromStr;
 use super::triangle::Triangle;
-use super::{Vec3, Solid};
+use super::{Vec3, Solid, Hit, Aabb};
+use super::{ParseError, fields, field_count, parse_field};
 
 pub struct Rectangle {
     pub p0: Vec3<f64>,
@@ -19,36 +20,123 @@ impl Rectangle {
 
         Rectangle {p0, p1, p2, p3, t0, t1}
     }
+
+    // Inverts p0 + u (p2 - p0) + v (p1 - p0) + u v (p3 - p2 - p1 + p0), the
+    // bilinear patch spanned by the corners, within the plane of `t0`
+    fn bilinear_uv(&self, p: Vec3<f64>) -> (f64, f64) {
+        let mut x = self.p2 - self.p0;
+        x.normalize();
+        let y = self.t0.normal.cross(x);
+        let flat = |a: Vec3<f64>| (a.dot(&x), a.dot(&y));
+        let cross = |a: (f64, f64), b: (f64, f64)| a.0 * b.1 - a.1 * b.0;
+
+        let e = flat(self.p2 - self.p0);
+        let f = flat(self.p1 - self.p0);
+        let g = flat(self.p3 - self.p2 - self.p1 + self.p0);
+        let h = flat(p - self.p0);
+        let k2 = cross(g, f);
+        let k1 = cross(e, f) + cross(h, g);
+        let k0 = cross(h, e);
+
+        let u_of = |v: f64| {
+            let (dx, dy) = (e.0 + g.0 * v, e.1 + g.1 * v);
+            if dx.abs() > dy.abs() {(h.0 - f.0 * v) / dx} else {(h.1 - f.1 * v) / dy}
+        };
+        // Parallelograms are linear
+        if k2.abs() <= 1e-9 * cross(e, f).abs() {
+            let v = -k0 / k1;
+            return (u_of(v), v);
+        }
+        let w = (k1 * k1 - 4. * k0 * k2).max(0.).sqrt();
+        let v = (-k1 - w) / (2. * k2);
+        let u = u_of(v);
+        if (0. ..=1.).contains(&u) && (0. ..=1.).contains(&v) {
+            return (u, v);
+        }
+        let v = (-k1 + w) / (2. * k2);
+        (u_of(v), v)
+    }
 }
 
 impl Solid for Rectangle {
-    fn intersect(&self, org: Vec3<f64>, dir: Vec3<f64>) -> Option<f64> {
-        match self.t0.intersect(org, dir) {
-            r @ Some(_) => r,
-            None => self.t1.intersect(org, dir)
-        }
+    // u goes from p0 to p2 and v from p0 to p1, bilinearly so that uvs are
+    // continuous across the diagonal. The primitive is the triangle hit.
+    fn intersect(&self, org: Vec3<f64>, dir: Vec3<f64>, t_min: f64, t_max: f64) -> Option<Hit> {
+        // The triangles are wound differently, t0 orients both
+        let normal = self.t0.normal;
+        let (h, primitive) = match self.t0.intersect(org, dir, t_min, t_max) {
+            Some(h) => (h, 0),
+            None => (self.t1.intersect(org, dir, t_min, t_max)?, 1)
+        };
+        Some(Hit::new(org, dir, h.t, normal, self.bilinear_uv(h.point), primitive))
     }
 
     fn position(&self) -> Vec3<f64> {self.p0}
 
-    fn normal_at(&self, hit: Vec3<f64>, dir: Vec3<f64>) -> Vec3<f64> {
-        self.t0.normal_at(hit, dir)
+    fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(&[self.p0, self.p1, self.p2, self.p3])
+    }
+
+    fn tangents_at(&self, hit: &Hit) -> (Vec3<f64>, Vec3<f64>) {
+        let (u, v) = hit.uv;
+        let g = self.p3 - self.p2 - self.p1 + self.p0;
+        (self.p2 - self.p0 + g * v, self.p1 - self.p0 + g * u)
+    }
+
+    fn area(&self) -> f64 {
+        self.t0.area() + self.t1.area()
+    }
+
+    fn sample_surface(&self, u: f64, v: f64) -> (Vec3<f64>, Vec3<f64>) {
+        // Pick a triangle proportionally to its area and reuse `u`
+        let p = self.t0.area() / self.area();
+        if u < p {
+            self.t0.sample_surface(u / p, v)
+        } else {
+            self.t1.sample_surface((u - p) / (1. - p), v)
+        }
     }
 }
 
 impl FromStr for Rectangle {
-    type Err = ();
+    type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let vectors = s.split(", ").collect::<Vec<&str>>();
+        let vectors = fields(s);
         
         if vectors.len() != 4 {
-            return Err(());
+            return Err(field_count(s, 4, "rectangle"));
         }
 
-        let p0 = Vec3::from_str(vectors[0]).or_else(|_| Err(()))?;
-        let p1 = Vec3::from_str(vectors[1]).or_else(|_| Err(()))?;
-        let p2 = Vec3::from_str(vectors[2]).or_else(|_| Err(()))?;
-        let p3 = Vec3::from_str(vectors[3]).or_else(|_| Err(()))?;
+        let p0 = parse_field(s, vectors[0])?;
+        let p1 = parse_field(s, vectors[1])?;
+        let p2 = parse_field(s, vectors[2])?;
+        let p3 = parse_field(s, vectors[3])?;
         Ok(Self::new(p0, p1, p2, p3)) 
     }
 }
+
+#[test]
+fn rectangle_uv_test() {
+    // Trapezoid narrowing towards p2 and p3, the diagonal running from p1 to
+    // p2
+    let trapezoid = Rectangle::new(Vec3::new(0., 0., 0.), Vec3::new(0., 2., 0.),
+                                   Vec3::new(2., 0.5, 0.), Vec3::new(2., 1.5, 0.));
+    let hit = |x: f64, y: f64| trapezoid.intersect(Vec3::new(x, y, 1.), Vec3::new(0., 0., -1.),
+                                                   0., f64::INFINITY).unwrap();
+    let close = |a: (f64, f64), b: (f64, f64)| (a.0 - b.0).abs() < 1e-6 && (a.1 - b.1).abs() < 1e-6;
+
+    assert!(close(hit(1e-9, 1e-9).uv, (0., 0.)));
+    assert!(close(hit(2. - 1e-9, 1.).uv, (1., 0.5)));
+    // Halfway along u, the edges are 1.5 apart
+    assert!(close(hit(1., 0.25 + 0.375).uv, (0.5, 0.25)));
+
+    // No seam along the diagonal, where the triangles meet
+    let (h0, h1) = (hit(1. - 1e-9, 1.25 - 1e-9), hit(1. + 1e-9, 1.25 + 1e-9));
+    let ((a, b), (c, d)) = (trapezoid.tangents_at(&h0), trapezoid.tangents_at(&h1));
+    assert!(h0.primitive != h1.primitive && (a - c).len() < 1e-6 && (b - d).len() < 1e-6);
+
+    // Along the u = 1 edge, v spans its length
+    let (dpdu, dpdv) = trapezoid.tangents_at(&hit(2. - 1e-9, 1.));
+    assert!((dpdu - Vec3::new(2., 0., 0.)).len() < 1e-6);
+    assert!((dpdv - Vec3::new(0., 1., 0.)).len() < 1e-6);
+}
@@ -1,5 +1,7 @@
 use std::str::FromStr;
-use super::{Vec3, Solid};
+use std::f64::consts::PI;
+use super::{Vec3, Solid, Hit, Aabb, sample_area_from, basis};
+use super::{ParseError, fields, field_count, parse_field, parse_number};
 
 pub struct Sphere {
     pub center: Vec3<f64>,
@@ -11,66 +13,154 @@ impl Sphere {
     pub fn new(center: Vec3<f64>, radius: f64) -> Self {
         Sphere {center, radius, radius2: radius * radius}
     }
+
+    // Longitude and colatitude of a unit normal, the poles being along the
+    // y axis
+    fn uv(n: Vec3<f64>) -> (f64, f64) {
+        let u = 0.5 + n.x.atan2(n.z) / (2. * PI);
+        let v = n.y.clamp(-1., 1.).acos() / PI;
+        (u, v)
+    }
 }
 
 impl Solid for Sphere {
-    fn intersect(&self, org: Vec3<f64>, dir: Vec3<f64>) -> Option<f64> {
+    fn intersect(&self, org: Vec3<f64>, dir: Vec3<f64>, t_min: f64, t_max: f64) -> Option<Hit> {
         let l = self.center - org;
         let tca = l.dot(&dir);
-        if tca < 0. {return None;};
         let d2 = l.dot(&l) - tca * tca;
         if d2 > self.radius2 {return None;};
         let thc = (self.radius2 - d2).sqrt();
-        let t0 = tca - thc;
-        let t1 = tca + thc;
-        if t0 > t1 {
-            if t1 < 0. {if t0 < 0. {None} else {Some(t0)}} else {Some(t1)}
-        } else {
-            if t0 < 0. {if t1 < 0. {None} else {Some(t1)}} else{Some(t0)}
-        }
-        /*
-        let l = org - self.center;
-        match solve_quadratic(&Vec3::new(dir.dot(&dir), 2. * dir.dot(&l),
-            l.dot(&l) - self.radius2)) {
-
-            None => None,
-            Some((t0, t1)) => {
-                let (t0, t1) = if t0 > t1 {(t1, t0)} else {(t0, t1)};
-                if t0 < 0. {
-                    if t1 < 0. {None} else {Some(t1)}
-                } else {
-                    Some(t0)
-                }
-            }
+        // The far root is the hit for rays starting inside
+        let t = if tca - thc >= t_min {tca - thc} else {tca + thc};
+        if t < t_min || t > t_max {
+            return None;
         }
-        */
+        let mut n = org + dir * t - self.center;
+        n.normalize();
+        Some(Hit::new(org, dir, t, n, Self::uv(n), 0))
     }
-    /*
-    fn emission_color(&self) -> Vec3<f64> {self.emission_color}
-    fn surface_color(&self) -> Vec3<f64> {self.surface_color}
-    fn transparency(&self) -> f64 {self.transparency}
-    fn reflection(&self) -> f64 {self.reflection}
-    */
+
     fn position(&self) -> Vec3<f64> {self.center}
 
-    fn normal_at(&self, hit: Vec3<f64>, _dir: Vec3<f64>) -> Vec3<f64> {
-        let mut res = hit - self.center;
-        res.normalize();
-        res
+    fn bounding_box(&self) -> Aabb {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Aabb::new(self.center - r, self.center + r)
+    }
+
+    fn tangents_at(&self, hit: &Hit) -> (Vec3<f64>, Vec3<f64>) {
+        let pi = PI;
+        let mut n = hit.point - self.center;
+        n.normalize();
+        let sin_theta = (n.x * n.x + n.z * n.z).sqrt();
+        // Longitude is undefined at the poles
+        if sin_theta < 1e-9 {
+            return (Vec3::new(2. * pi * self.radius, 0., 0.),
+                    Vec3::new(0., 0., pi * self.radius * n.y));
+        }
+        let (sin_phi, cos_phi) = (n.x / sin_theta, n.z / sin_theta);
+        let dpdu = Vec3::new(n.z, 0., -n.x) * (2. * pi * self.radius);
+        let dpdv = Vec3::new(n.y * sin_phi, -sin_theta, n.y * cos_phi) * (pi * self.radius);
+        (dpdu, dpdv)
+    }
+
+    fn area(&self) -> f64 {
+        4. * PI * self.radius2
+    }
+
+    fn sample_surface(&self, u: f64, v: f64) -> (Vec3<f64>, Vec3<f64>) {
+        let z = 1. - 2. * u;
+        let r = (1. - z * z).max(0.).sqrt();
+        let phi = 2. * PI * v;
+        let n = Vec3::new(r * phi.cos(), r * phi.sin(), z);
+        (self.center + n * self.radius, n)
+    }
+
+    // Uniform over the cone of directions the sphere subtends from `p`,
+    // which wastes no samples on the hidden side
+    fn sample_from(&self, p: Vec3<f64>, u: f64, v: f64) -> Option<(Vec3<f64>, Vec3<f64>, f64)> {
+        let mut wc = self.center - p;
+        let dc2 = wc.len_sqr();
+        if dc2 <= self.radius2 {
+            return sample_area_from(self, p, u, v);
+        }
+        let dc = dc2.sqrt();
+        wc.normalize();
+
+        let cos_max = (1. - self.radius2 / dc2).max(0.).sqrt();
+        let cos_theta = (1. - u) + u * cos_max;
+        let sin2_theta = (1. - cos_theta * cos_theta).max(0.);
+        let phi = 2. * PI * v;
+        let (t, b) = basis(wc);
+        let dir = t * (sin2_theta.sqrt() * phi.cos()) + b * (sin2_theta.sqrt() * phi.sin()) +
+            wc * cos_theta;
+
+        // Nearest intersection along `dir`, clamped for grazing directions
+        let dist = dc * cos_theta - (self.radius2 - dc2 * sin2_theta).max(0.).sqrt();
+        let q = p + dir * dist;
+        let mut n = q - self.center;
+        n.normalize();
+        Some((q, n, 1. / (2. * PI * (1. - cos_max))))
     }
 }
 
 impl FromStr for Sphere {
-    type Err = ();
+    type Err = ParseError;
     fn from_str(s:  &str) -> Result<Self, Self::Err> {
-        //let ind = s.rfind(' ').ok_or(())?;
-        //let (center_str, radius_str) = s.split_at(ind);
-        let vals = s.split(", ").collect::<Vec<&str>>();
+        let vals = fields(s);
         if vals.len() != 2 {
-            return Err(());
+            return Err(field_count(s, 2, "sphere"));
         }
-        let center = Vec3::from_str(vals[0]).or_else(|_| Err(()))?;
-        let radius = f64::from_str(vals[1].trim()).or_else(|_| Err(()))?;
+        let center = parse_field(s, vals[0])?;
+        let radius = parse_number(s, vals[1], "radius")?;
         Ok(Self::new(center, radius))
     }
 }
+
+#[test]
+fn sphere_uv_test() {
+    let pi = PI;
+    let s = Sphere::new(Vec3::new(1., 2., 3.), 2.);
+    let at = |u: f64, v: f64| {
+        let (phi, theta) = (2. * pi * (u - 0.5), pi * v);
+        s.center + Vec3::new(theta.sin() * phi.sin(), theta.cos(), theta.sin() * phi.cos()) * 2.
+    };
+
+    let (u, v) = (0.3, 0.6);
+    let p = at(u, v);
+    let mut dir = p - Vec3::new(-5., 1., 0.);
+    dir.normalize();
+    let hit = s.intersect(Vec3::new(-5., 1., 0.), dir, 0., f64::INFINITY).unwrap();
+    assert!((hit.point - p).len() < 1e-9 && hit.front_face);
+    assert!((hit.uv.0 - u).abs() < 1e-9 && (hit.uv.1 - v).abs() < 1e-9);
+
+    // Tangents match finite differences of the parameterisation
+    let h = 1e-6;
+    let (dpdu, dpdv) = s.tangents_at(&hit);
+    assert!(((at(u + h, v) - p) * (1. / h) - dpdu).len() < 1e-4);
+    assert!(((at(u, v + h) - p) * (1. / h) - dpdv).len() < 1e-4);
+
+    // Rays from inside, including ones leaving away from the center
+    let inside = s.intersect(s.center + Vec3::new(1., 0., 0.), Vec3::new(1., 0., 0.), 0.,
+                             f64::INFINITY).unwrap();
+    assert!((inside.t - 1.).abs() < 1e-9 && !inside.front_face);
+    assert!(inside.normal.x < 0.);
+}
+
+#[test]
+fn sphere_sampling_test() {
+    let s = Sphere::new(Vec3::new(0., 0., -5.), 3.);
+    let p = Vec3::default();
+    for k in 0..64 {
+        let (u, v) = ((k % 8) as f64 / 8. + 0.01, (k / 8) as f64 / 8. + 0.02);
+        let (q, n, pdf) = s.sample_from(p, u, v).unwrap();
+        assert!(((q - s.center).len() - 3.).abs() < 1e-9);
+        // On the visible side, where rays from `p` actually hit
+        let mut dir = q - p;
+        let dist = dir.len();
+        dir.normalize();
+        assert!(n.dot(&dir) < 0.);
+        assert!((s.intersect(p, dir, 0., f64::INFINITY).unwrap().t - dist).abs() < 1e-9);
+        // The pdf is uniform over the cone, 0.8 being its half angle's cosine
+        assert!((pdf * 2. * PI * 0.2 - 1.).abs() < 1e-9);
+    }
+}
@@ -1,5 +1,6 @@
 use std::str::FromStr;
-use super::{Vec3, Solid};
+use super::{Vec3, Solid, Hit, Aabb};
+use super::{ParseError, fields, field_count, parse_field};
 
 pub struct Triangle {
     pub p0: Vec3<f64>,
@@ -20,58 +21,80 @@ impl Triangle {
     }
 }
 
+// Möller-Trumbore, returns the distance and the barycentric coordinates of
+// the hit along the `u` and `v` edges
+pub fn intersect_triangle(org: Vec3<f64>, dir: Vec3<f64>, p0: Vec3<f64>, u: Vec3<f64>,
+                          v: Vec3<f64>) -> Option<(f64, f64, f64)> {
+    let epsilon = 1e-8f64;
+
+    let h = dir.cross(v);
+    let a = u.dot(&h);
+    if a > -epsilon && a < epsilon {
+        return None;
+    }
+    let f = 1. / a;
+    let s = org - p0;
+    let w = f * s.dot(&h);
+
+    if !(0. ..=1.).contains(&w) {
+        return None;
+    }
+    let q = s.cross(u);
+    let z = f * dir.dot(&q);
+    if z < 0. || w + z > 1. {
+        return None;
+    }
+    let t = f * v.dot(&q);
+    if t > epsilon {
+        Some((t, w, z))
+    } else {
+        None
+    }
+}
+
 impl Solid for Triangle {
     fn position(&self) -> Vec3<f64> {self.p0}
 
-    fn intersect(&self, org: Vec3<f64>, dir: Vec3<f64>) -> Option<f64> {
-        let epsilon = 1e-8f64;
+    fn area(&self) -> f64 {
+        self.u.cross(self.v).len() * 0.5
+    }
 
-        let h = dir.cross(self.v);
-        let a = self.u.dot(&h);
-        if a > -epsilon && a < epsilon {
-            return None;
-        }
-        let f = 1. / a;
-        let s = org - self.p0;
-        let w = f * s.dot(&h);
+    fn sample_surface(&self, u: f64, v: f64) -> (Vec3<f64>, Vec3<f64>) {
+        let su = u.sqrt();
+        (self.p0 + self.u * (su * (1. - v)) + self.v * (su * v), self.normal)
+    }
 
-        if w < 0. || w > 1. {
-            return None;
-        }
-        let q = s.cross(self.u);
-        let z = f * dir.dot(&q);
-        if z < 0. || w + z > 1. {
-            return None;
-        }
-        let t = f * self.v.dot(&q);
-        if t > epsilon {
-            Some(t)
-        } else {
-            None
-        }
+    fn bounding_box(&self) -> Aabb {
+        Aabb::from_points(&[self.p0, self.p1, self.p2])
     }
 
-    fn normal_at(&self, _p: Vec3<f64>, dir: Vec3<f64>) -> Vec3<f64> {
-        if self.normal.dot(&dir) > 0. {
-            -self.normal
-        } else {
-            self.normal
+    // Barycentric uv coordinates
+    fn intersect(&self, org: Vec3<f64>, dir: Vec3<f64>, t_min: f64, t_max: f64) -> Option<Hit> {
+        match intersect_triangle(org, dir, self.p0, self.u, self.v) {
+            Some((t, w, z)) if t >= t_min && t <= t_max => {
+                Some(Hit::new(org, dir, t, self.normal, (w, z), 0))
+            },
+            _ => None
         }
     }
+
+    fn tangents_at(&self, _hit: &Hit) -> (Vec3<f64>, Vec3<f64>) {
+        (self.u, self.v)
+    }
 }
 
 impl FromStr for Triangle {
-    type Err = ();
+    type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let vectors = s.split(", ").collect::<Vec<&str>>();
+        let vectors = fields(s);
 
         if vectors.len() != 3 {
-            return Err(());
+            return Err(field_count(s, 3, "triangle"));
         }
 
-        let p0 = Vec3::from_str(vectors[0]).or_else(|_| Err(()))?;
-        let p1 = Vec3::from_str(vectors[1]).or_else(|_| Err(()))?;
-        let p2 = Vec3::from_str(vectors[2]).or_else(|_| Err(()))?;
+        let p0 = parse_field(s, vectors[0])?;
+        let p1 = parse_field(s, vectors[1])?;
+        let p2 = parse_field(s, vectors[2])?;
         Ok(Self::new(p0, p1, p2)) 
     }
 }
@@ -1,5 +1,11 @@
+use std::str::FromStr;
+use std::sync::Arc;
 use super::Vec3;
 use super::vec3::Lerp;
+use super::noise::{Noise, Fractal};
+use super::texture::{Texture, MipMap, Wrap, Filtering};
+use super::error::{Error, ParseError, column_of, fields, field_count, parse_field, parse_number,
+                   rest};
 use super::rand::{
     Rng,
     SeedableRng,
@@ -9,24 +15,212 @@ use super::rand::{
 pub const PERMUTATIONS: usize = 512;
 pub const TAU: f64 = 2. * ::std::f64::consts::PI;
 
+// Where a surface is looked up
+pub struct TexCoords {
+    pub uv: (f64, f64),
+    // World space position
+    pub point: Vec3<f64>,
+    // Position relative to the object
+    pub local: Vec3<f64>,
+    // Derivatives of `uv` along the two axes of the pixel footprint, zero
+    // for point lookups
+    pub duv: [(f64, f64); 2]
+}
+
+impl TexCoords {
+    // For flat 2D lookups, the point being on the z = 0 plane
+    pub fn from_uv(x: f64, y: f64) -> Self {
+        let p = Vec3::new(x, y, 0.);
+        TexCoords {uv: (x, y), point: p, local: p, duv: [(0., 0.); 2]}
+    }
+
+    // Moved by `du` along the surface's u direction, `dpdu` being the
+    // derivative of the position along it
+    fn shifted(&self, du: f64, dpdu: Vec3<f64>, dv: f64, dpdv: Vec3<f64>) -> Self {
+        let dp = dpdu * du + dpdv * dv;
+        TexCoords {uv: (self.uv.0 + du, self.uv.1 + dv), point: self.point + dp,
+            local: self.local + dp, duv: self.duv}
+    }
+}
+
+#[derive(Clone)]
 pub enum Surface {
     Solid(Vec3<f64>),
-    Noise(NoiseSurface)
+    Noise(NoiseSurface),
+    Fractal(FractalSurface),
+    Pattern(PatternSurface),
+    Image(ImageSurface)
 }
 
 use self::Surface::*;
 
 impl Surface {
     pub fn color_at(&self, x: f64, y: f64) -> Vec3<f64> {
-        match self {
-            &Solid(c) => c,
-            &Noise(ref s) => s.color_at(x, y)
+        self.eval(&TexCoords::from_uv(x, y))
+    }
+
+    pub fn eval(&self, tc: &TexCoords) -> Vec3<f64> {
+        match *self {
+            Solid(c) => c,
+            Noise(ref s) => s.color_at(tc.uv.0 * s.scale, tc.uv.1 * s.scale),
+            Fractal(ref s) => s.color_at(if s.object_space {tc.local} else {tc.point}),
+            Pattern(ref s) => s.color_at(if s.object_space {tc.local} else {tc.point}),
+            Image(ref s) => s.color_at(tc.uv, tc.duv)
+        }
+    }
+
+    // Mean of the color channels, for surfaces used as scalar textures
+    pub fn value(&self, tc: &TexCoords) -> f64 {
+        let c = self.eval(tc);
+        (c.x + c.y + c.z) / 3.
+    }
+}
+
+// solid, color
+// noise, seed, scale, color
+// fbm|turbulence|ridged, perlin|simplex, seed, scale, octaves, lacunarity, gain, color[, object]
+// checker|stripes, scale, stops...[, object]
+// bricks, scale, mortar, stops...[, object]
+// rings|marble, seed, scale, distortion, stops...[, object]
+// cells, seed, scale, stops...[, object]
+// where each colour stop is `position r g b`
+// image, path.png[, scale][, repeat|clamp][, srgb|linear][, bilinear|trilinear|ewa]
+impl FromStr for Surface {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = fields(s);
+        match vals[0] {
+            "solid" => {
+                if vals.len() != 2 {
+                    return Err(field_count(s, 2, "solid surface"));
+                }
+                Ok(Solid(parse_field::<Vec3<f64>>(s, vals[1])? * (1. / 255.)))
+            },
+            "noise" => {
+                if vals.len() != 4 {
+                    return Err(field_count(s, 4, "noise surface"));
+                }
+                let seed = parse_number(s, vals[1], "seed")?;
+                let scale = parse_number(s, vals[2], "scale")?;
+                let color = parse_field::<Vec3<f64>>(s, vals[3])? * (1. / 255.);
+                Ok(Noise(NoiseSurface::new_seeded(color, seed).with_scale(scale)))
+            },
+            k @ "fbm" | k @ "turbulence" | k @ "ridged" => {
+                if vals.len() != 8 && vals.len() != 9 {
+                    return Err(field_count(s, 8, "fractal surface"));
+                }
+                let kind = match k {
+                    "fbm" => FractalKind::Fbm,
+                    "turbulence" => FractalKind::Turbulence,
+                    _ => FractalKind::Ridged
+                };
+                let fractal = Fractal {
+                    basis: parse_field(s, vals[1])?,
+                    octaves: parse_number(s, vals[4], "octave count")?,
+                    lacunarity: parse_number(s, vals[5], "lacunarity")?,
+                    gain: parse_number(s, vals[6], "gain")?
+                };
+                let seed = parse_number(s, vals[2], "seed")?;
+                let color = parse_field::<Vec3<f64>>(s, vals[7])? * (1. / 255.);
+                let mut surface = FractalSurface::new_seeded(color, kind, seed);
+                surface.fractal = fractal;
+                surface.scale = parse_number(s, vals[3], "scale")?;
+                surface.object_space = match vals.get(8) {
+                    Some(t) => parse_space(s, t)?,
+                    None => false
+                };
+                Ok(Fractal(surface))
+            },
+            "checker" | "stripes" | "bricks" | "rings" | "marble" | "cells" => {
+                Ok(Pattern(parse_pattern(s, &vals)?))
+            },
+            "image" => Ok(Image(parse_image(s, &vals, true)?)),
+            t => Err(ParseError::new("Unknown surface type", t).at_column(column_of(s, t)))
+        }
+    }
+}
+
+// `world` or `object`, whether a solid texture is evaluated relative to the
+// object
+fn parse_space(s: &str, field: &str) -> Result<bool, ParseError> {
+    match field {
+        "world" => Ok(false),
+        "object" => Ok(true),
+        t => Err(ParseError::new("Expected world or object space", t).at_column(column_of(s, t)))
+    }
+}
+
+fn parse_pattern(s: &str, vals: &[&str]) -> Result<PatternSurface, ParseError> {
+    // Noise based patterns take a seed, some a parameter after the scale
+    let (seeded, param) = match vals[0] {
+        "checker" | "stripes" => (false, None),
+        "bricks" => (false, Some("mortar width")),
+        "rings" | "marble" => (true, Some("distortion")),
+        _ => (true, None)
+    };
+    let leading = 2 + seeded as usize + param.is_some() as usize;
+    let mut stops = &vals[leading.min(vals.len())..];
+    let object_space = match stops.last() {
+        Some(&t) if t == "world" || t == "object" => {
+            stops = &stops[..stops.len() - 1];
+            parse_space(s, t)?
+        },
+        _ => false
+    };
+    if stops.is_empty() {
+        return Err(field_count(s, leading + 1, "pattern surface"));
+    }
+
+    let seed = if seeded {parse_number(s, vals[1], "seed")?} else {0};
+    let i = 1 + seeded as usize;
+    let p = match param {
+        Some(what) => parse_number(s, vals[i + 1], what)?,
+        None => 0.
+    };
+    let pattern = match vals[0] {
+        "checker" => PatternKind::Checker,
+        "stripes" => PatternKind::Stripes,
+        "bricks" => PatternKind::Bricks {mortar: p},
+        "rings" => PatternKind::Rings {distortion: p},
+        "marble" => PatternKind::Marble {distortion: p},
+        _ => PatternKind::Cells
+    };
+    let mut surface = PatternSurface::new_seeded(pattern, Ramp::from_fields(s, stops)?, seed);
+    surface.scale = parse_number(s, vals[i], "scale")?;
+    surface.object_space = object_space;
+    Ok(surface)
+}
+
+// `srgb` is the default encoding
+fn parse_image(s: &str, vals: &[&str], srgb: bool) -> Result<ImageSurface, ParseError> {
+    let path = vals.get(1).cloned().unwrap_or("");
+    let (mut scale, mut wrap, mut srgb) = (1., Wrap::Repeat, srgb);
+    let mut filtering = Filtering::Trilinear;
+    for &v in vals.iter().skip(2) {
+        match v {
+            "srgb" => {srgb = true;},
+            "linear" => {srgb = false;},
+            "repeat" | "clamp" => {wrap = parse_field(s, v)?;},
+            "bilinear" | "trilinear" | "ewa" => {filtering = parse_field(s, v)?;},
+            _ => {scale = parse_number(s, v, "image scale")?;}
         }
     }
+    let texture = Texture::load_png(path, srgb).map_err(|e| {
+        let reason = match e {
+            Error::Io {ref source, ..} => source.to_string(),
+            e => e.to_string()
+        };
+        ParseError::new(&format!("Cannot load image ({})", reason), path)
+            .at_column(column_of(s, path))
+    })?;
+    Ok(ImageSurface {texture: Arc::new(MipMap::new(texture)), scale, wrap, filtering})
 }
 
+#[derive(Clone)]
 pub struct NoiseSurface {
     pub color: Vec3<f64>,
+    // Noise features per unit of surface coordinates
+    pub scale: f64,
     pub values: Vec<(f64, f64)>,
     perm_table: Vec<usize>
 }
@@ -38,7 +232,9 @@ fn dot(p0: (f64, f64), p1: (f64, f64)) -> f64 {
 impl NoiseSurface {
     pub fn new_seeded(color: Vec3<f64>, seed: u64) -> Self {
         let perm_mask = PERMUTATIONS - 1;
-        let s = [(seed / (1 << 32)) as u32, (seed & (1 << 32 - 1)) as u32];
+        // Only bit 31 of the low word has ever been used, kept so seeded
+        // textures render as they always have
+        let s = [(seed / (1 << 32)) as u32, (seed & (1 << 31)) as u32];
         let mut rng = IsaacRng::from_seed(&s);
         let mut values: Vec<(f64, f64)> = vec![(0., 0.); PERMUTATIONS];
         let mut perm_table = vec![0; PERMUTATIONS * 2];
@@ -67,7 +263,12 @@ impl NoiseSurface {
             perm_table[k + PERMUTATIONS] = perm_table[k];
         }
 
-        NoiseSurface {color, values, perm_table}
+        NoiseSurface {color, scale: 1., values, perm_table}
+    }
+
+    pub fn with_scale(mut self, scale: f64) -> Self {
+        self.scale = scale;
+        self
     }
 
     fn hash(&self, x: usize, y: usize) -> usize {
@@ -76,11 +277,11 @@ impl NoiseSurface {
 
     pub fn color_at(&self, x: f64, y: f64) -> Vec3<f64> {
         let perm_mask = PERMUTATIONS - 1;
-        let xi = (x.floor() as usize) & perm_mask;
-        let yi = (y.floor() as usize) & perm_mask;
+        let xi = (x.floor() as i64 as usize) & perm_mask;
+        let yi = (y.floor() as i64 as usize) & perm_mask;
 
-        let tx = x - x.floor() as f64;
-        let ty = y - y.floor() as f64;
+        let tx = x - x.floor();
+        let ty = y - y.floor();
 
         let rx0 = xi & perm_mask;
         let rx1 = (xi + 1) & perm_mask;
@@ -105,7 +306,7 @@ impl NoiseSurface {
         let nx0 = dot(c00, p00).slerp(dot(c10, p10), tx);
         let nx1 = dot(c01, p01).slerp(dot(c11, p11), tx);
         
-        let res = nx0.slerp(nx1, ty) as f64;
+        let res = nx0.slerp(nx1, ty);
         /*
         if res < 0. {
             println!("less than 0");
@@ -115,3 +316,311 @@ impl NoiseSurface {
         self.color * ((res + 1.) * 0.5)
     }
 }
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FractalKind {
+    Fbm,
+    Turbulence,
+    Ridged
+}
+
+// Solid texture, free of the seams and stretching of uv mapped noise
+#[derive(Clone)]
+pub struct FractalSurface {
+    pub color: Vec3<f64>,
+    pub kind: FractalKind,
+    pub fractal: Fractal,
+    pub noise: Noise,
+    // Noise features per world unit
+    pub scale: f64,
+    // Evaluated relative to the object instead of in world space, so the
+    // texture moves along with it
+    pub object_space: bool,
+    // Fourth noise dimension, for animation
+    pub time: Option<f64>
+}
+
+impl FractalSurface {
+    pub fn new_seeded(color: Vec3<f64>, kind: FractalKind, seed: u64) -> Self {
+        FractalSurface {color, kind, fractal: Fractal::default(), noise: Noise::new_seeded(seed),
+            scale: 1., object_space: false, time: None}
+    }
+
+    // In [0, 1]
+    pub fn value_at(&self, p: Vec3<f64>) -> f64 {
+        let p = p * self.scale;
+        match self.kind {
+            FractalKind::Fbm => (self.fractal.fbm(&self.noise, p, self.time) + 1.) * 0.5,
+            FractalKind::Turbulence => self.fractal.turbulence(&self.noise, p, self.time),
+            FractalKind::Ridged => self.fractal.ridged(&self.noise, p, self.time)
+        }
+    }
+
+    pub fn color_at(&self, p: Vec3<f64>) -> Vec3<f64> {
+        self.color * self.value_at(p).clamp(0., 1.)
+    }
+}
+
+// Texture mapped with the solid's uv coordinates, shared between the objects
+// using it
+#[derive(Clone)]
+pub struct ImageSurface {
+    pub texture: Arc<MipMap>,
+    // Repetitions per unit of surface coordinates
+    pub scale: f64,
+    pub wrap: Wrap,
+    pub filtering: Filtering
+}
+
+impl ImageSurface {
+    pub fn color_at(&self, uv: (f64, f64), duv: [(f64, f64); 2]) -> Vec3<f64> {
+        let k = self.scale;
+        let duv = [(duv[0].0 * k, duv[0].1 * k), (duv[1].0 * k, duv[1].1 * k)];
+        self.texture.sample(uv.0 * k, uv.1 * k, duv, self.filtering, self.wrap)
+    }
+}
+
+// Perturbation of the shading normal
+#[derive(Clone)]
+pub enum Bump {
+    // Displacement along the normal by the surface's value times `strength`
+    Height {surface: Surface, strength: f64},
+    // Tangent space normals, x and y mapped from the red and green channels
+    // going along u and up the image, `strength` scaling them
+    Normal {map: ImageSurface, strength: f64}
+}
+
+impl Bump {
+    // `n` is the unperturbed shading normal and `dpdu`, `dpdv` the position
+    // derivatives at `tc`
+    pub fn apply(&self, tc: &TexCoords, n: Vec3<f64>, dpdu: Vec3<f64>, dpdv: Vec3<f64>)
+        -> Vec3<f64> {
+        let mut bumped = match *self {
+            Bump::Height {ref surface, strength} => {
+                // Forward differences over about half the footprint
+                let step = |a: f64, b: f64| {
+                    let s = 0.5 * (a.abs() + b.abs());
+                    if s > 0. {s} else {1e-3}
+                };
+                let du = step(tc.duv[0].0, tc.duv[1].0);
+                let dv = step(tc.duv[0].1, tc.duv[1].1);
+                let h = surface.value(tc);
+                let hu = surface.value(&tc.shifted(du, dpdu, 0., dpdv));
+                let hv = surface.value(&tc.shifted(0., dpdu, dv, dpdv));
+                // Derivatives of the displaced position, dropping the
+                // normal's own derivatives
+                let su = dpdu + n * ((hu - h) / du * strength);
+                let sv = dpdv + n * ((hv - h) / dv * strength);
+                su.cross(sv)
+            },
+            Bump::Normal {ref map, strength} => {
+                let c = map.color_at(tc.uv, tc.duv);
+                let (x, y, z) = (c.x * 2. - 1., c.y * 2. - 1., c.z * 2. - 1.);
+                let mut t = dpdu - n * n.dot(&dpdu);
+                t.normalize();
+                // v runs down images, normal maps' y axis up
+                let mut b = n.cross(t);
+                if b.dot(&dpdv) > 0. {
+                    b = -b;
+                }
+                t * (x * strength) + b * (y * strength) + n * z
+            }
+        };
+        if bumped.len_sqr() == 0. {
+            return n;
+        }
+        bumped.normalize();
+        if bumped.dot(&n) < 0. {-bumped} else {bumped}
+    }
+}
+
+// height, strength, surface definition
+// normal, strength, image, path.png[, options]
+impl FromStr for Bump {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = fields(s);
+        if vals.len() < 3 {
+            return Err(field_count(s, 3, "bump"));
+        }
+        let strength = parse_number(s, vals[1], "bump strength")?;
+        let def = rest(s, &vals, 2);
+        match vals[0] {
+            "height" => Ok(Bump::Height {surface: parse_field(s, def)?, strength}),
+            "normal" if vals[2] == "image" => {
+                // Normal maps hold vectors, not colors
+                Ok(Bump::Normal {map: parse_image(s, &vals[2..], false)?, strength})
+            },
+            "normal" => Err(ParseError::new("Expected an image normal map", vals[2])
+                            .at_column(column_of(s, vals[2]))),
+            t => Err(ParseError::new("Unknown bump type, expected height or normal", t)
+                     .at_column(column_of(s, t)))
+        }
+    }
+}
+
+// Colour stops sorted by position, linearly interpolated in between and
+// constant past either end
+#[derive(Clone, PartialEq, Debug)]
+pub struct Ramp {
+    pub stops: Vec<(f64, Vec3<f64>)>
+}
+
+impl Ramp {
+    pub fn new(mut stops: Vec<(f64, Vec3<f64>)>) -> Self {
+        stops.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(::std::cmp::Ordering::Equal));
+        Ramp {stops}
+    }
+
+    pub fn two(from: Vec3<f64>, to: Vec3<f64>) -> Self {
+        Ramp::new(vec![(0., from), (1., to)])
+    }
+
+    // `fields` are subslices of `s`, each one stop
+    pub fn from_fields(s: &str, fields: &[&str]) -> Result<Self, ParseError> {
+        let mut stops = Vec::with_capacity(fields.len());
+        for f in fields {
+            let pos = f.split_whitespace().next().unwrap_or(f);
+            let color = parse_field::<Vec3<f64>>(s, &f[pos.len()..])
+                .map_err(|e| e.context("colour stop"))?;
+            stops.push((parse_number(s, pos, "colour stop position")?, color * (1. / 255.)));
+        }
+        Ok(Ramp::new(stops))
+    }
+
+    pub fn eval(&self, t: f64) -> Vec3<f64> {
+        match self.stops.iter().position(|s| s.0 > t) {
+            None => self.stops.last().map_or(Vec3::default(), |s| s.1),
+            Some(0) => self.stops[0].1,
+            Some(i) => {
+                let ((t0, c0), (t1, c1)) = (self.stops[i - 1], self.stops[i]);
+                c0.lerp(c1, (t - t0) / (t1 - t0))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PatternKind {
+    // Alternating unit cubes
+    Checker,
+    // Alternating unit slabs along x
+    Stripes,
+    // Running bond of 2 by 1 bricks in the xy plane, 0 in the `mortar` wide
+    // joints
+    Bricks {mortar: f64},
+    // Wood grain, unit spaced rings around the y axis
+    Rings {distortion: f64},
+    // Sine bands along x, perturbed by turbulence
+    Marble {distortion: f64},
+    // Distance to the closest Worley feature point
+    Cells
+}
+
+// Procedural solid texture, its value in [0, 1] mapped through a colour ramp
+#[derive(Clone)]
+pub struct PatternSurface {
+    pub pattern: PatternKind,
+    pub ramp: Ramp,
+    // Perturbation of rings and marble
+    pub fractal: Fractal,
+    pub noise: Noise,
+    // Pattern repetitions per world unit
+    pub scale: f64,
+    pub object_space: bool
+}
+
+impl PatternSurface {
+    pub fn new_seeded(pattern: PatternKind, ramp: Ramp, seed: u64) -> Self {
+        PatternSurface {pattern, ramp, fractal: Fractal::default(), noise: Noise::new_seeded(seed),
+            scale: 1., object_space: false}
+    }
+
+    pub fn value_at(&self, p: Vec3<f64>) -> f64 {
+        // Nudged off the lattice so planes at integer coordinates, common in
+        // scenes, don't flicker between cells
+        let p = p * self.scale + Vec3::new(1e-6, 1e-6, 1e-6);
+        match self.pattern {
+            PatternKind::Checker => ((p.x.floor() + p.y.floor() + p.z.floor()) as i64 & 1) as f64,
+            PatternKind::Stripes => (p.x.floor() as i64 & 1) as f64,
+            PatternKind::Bricks {mortar} => {
+                let row = p.y.floor();
+                let x = p.x * 0.5 + if row as i64 & 1 == 1 {0.5} else {0.};
+                let (fx, fy) = (x - x.floor(), p.y - row);
+                // Distances to the nearest vertical and horizontal joints
+                let dx = fx.min(1. - fx) * 2.;
+                let dy = fy.min(1. - fy);
+                if dx.min(dy) < mortar * 0.5 {0.} else {1.}
+            },
+            PatternKind::Rings {distortion} => {
+                let r = (p.x * p.x + p.z * p.z).sqrt() +
+                    distortion * self.fractal.turbulence(&self.noise, p, None);
+                r - r.floor()
+            },
+            PatternKind::Marble {distortion} => {
+                let x = p.x + distortion * self.fractal.turbulence(&self.noise, p, None);
+                0.5 + 0.5 * (x * ::std::f64::consts::PI).sin()
+            },
+            PatternKind::Cells => self.noise.worley3(p).0
+        }
+    }
+
+    pub fn color_at(&self, p: Vec3<f64>) -> Vec3<f64> {
+        self.ramp.eval(self.value_at(p).clamp(0., 1.))
+    }
+}
+
+#[test]
+fn pattern_test() {
+    let (black, white) = (Vec3::new(0., 0., 0.), Vec3::new(1., 1., 1.));
+    let ramp = Ramp::two(black, white);
+    assert_eq!(ramp.eval(-1.), black);
+    assert_eq!(ramp.eval(0.5), Vec3::new(0.5, 0.5, 0.5));
+    assert_eq!(ramp.eval(2.), white);
+
+    let checker = PatternSurface::new_seeded(PatternKind::Checker, ramp.clone(), 0);
+    assert_eq!(checker.color_at(Vec3::new(0.5, 0.5, 0.5)), black);
+    assert_eq!(checker.color_at(Vec3::new(-0.5, 0.5, 0.5)), white);
+
+    let bricks = PatternSurface::new_seeded(PatternKind::Bricks {mortar: 0.1}, ramp, 0);
+    assert_eq!(bricks.value_at(Vec3::new(1., 0.5, 0.)), 1.);
+    assert_eq!(bricks.value_at(Vec3::new(1., 1.02, 0.)), 0.);
+    // Joints of the next row are offset by half a brick
+    assert_eq!(bricks.value_at(Vec3::new(1.99, 0.5, 0.)), 0.);
+    assert_eq!(bricks.value_at(Vec3::new(1.99, 1.5, 0.)), 1.);
+
+    match Surface::from_str("marble, 7, 2, 4, 0 255 255 255, 1 0 0 0, object") {
+        Ok(Pattern(ref m)) => {
+            assert!(m.object_space);
+            assert_eq!(m.pattern, PatternKind::Marble {distortion: 4.});
+            assert_eq!(m.ramp.stops.len(), 2);
+        },
+        _ => panic!("expected a marble surface")
+    }
+    match Surface::from_str("cells, 7, 2, 0 255 255 255, 1 0 0 x") {
+        Err(e) => {assert_eq!((e.column, e.token.as_str()), (35, "x"));},
+        _ => panic!("expected a parse error")
+    }
+}
+
+#[test]
+fn bump_test() {
+    let (n, dpdu, dpdv) = (Vec3::new(0., 0., 1.), Vec3::new(1., 0., 0.), Vec3::new(0., -1., 0.));
+    let tc = TexCoords::from_uv(0.3, 0.6);
+    let flat = Bump::Height {surface: Solid(Vec3::new(0.5, 0.5, 0.5)), strength: 1.};
+    assert_eq!(flat.apply(&tc, n, dpdu, dpdv), n);
+
+    // Heights rising along x tilt the normal towards -x
+    let ramp = PatternSurface::new_seeded(PatternKind::Marble {distortion: 0.},
+                                          Ramp::two(Vec3::default(), Vec3::new(1., 1., 1.)), 0);
+    let slope = Bump::Height {surface: Pattern(ramp), strength: 0.1};
+    let b = slope.apply(&TexCoords::from_uv(0., 0.), n, dpdu, dpdv);
+    assert!(b.x < 0. && b.y.abs() < 1e-9 && b.z > 0.);
+
+    // A map pointing up the image tilts the normal towards -dpdv
+    let texel = Texture::new(1, 1, vec![Vec3::new(0.5, 1., 0.5)]).unwrap();
+    let map = ImageSurface {texture: Arc::new(MipMap::new(texel)), scale: 1., wrap: Wrap::Repeat,
+        filtering: Filtering::Bilinear};
+    let b = Bump::Normal {map, strength: 1.}.apply(&tc, n, dpdu, dpdv);
+    assert!(b.x.abs() < 1e-9 && b.y > 0.);
+}
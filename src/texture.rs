@@ -0,0 +1,311 @@
+use std::fs::File;
+use std::str::FromStr;
+use png::{Decoder, HasParameters, Transformations, ColorType};
+use super::vec3::{Vec3, Lerp};
+use super::tonemap::srgb_decode;
+use super::error::{Error, ParseError, Result};
+
+// Lookups outside of [0, 1]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Wrap {
+    Repeat,
+    Clamp
+}
+
+impl FromStr for Wrap {
+    type Err = ParseError;
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "repeat" => Ok(Wrap::Repeat),
+            "clamp" => Ok(Wrap::Clamp),
+            _ => Err(ParseError::new("Unknown wrap mode, expected repeat or clamp", s))
+        }
+    }
+}
+
+// Linear RGB texels, rows going top to bottom
+#[derive(Clone, Debug)]
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<Vec3<f64>>
+}
+
+impl Texture {
+    pub fn new(width: usize, height: usize, texels: Vec<Vec3<f64>>) -> Result<Self> {
+        if width == 0 || height == 0 || texels.len() != width * height {
+            return Err(Error::InvalidParameter(
+                format!("{} texels do not make a {}x{} texture", texels.len(), width, height)));
+        }
+        Ok(Texture {width, height, texels})
+    }
+
+    // 8 or 16 bit grey, grey alpha, RGB, RGBA or palette images, alpha being
+    // dropped. Samples are taken as sRGB encoded unless `srgb` is false.
+    pub fn load_png(path: &str, srgb: bool) -> Result<Self> {
+        let file = File::open(path).map_err(|e| Error::io(path, e))?;
+        let mut decoder = Decoder::new(file);
+        // Expands palettes and low bit depths but keeps 16 bit samples
+        decoder.set(Transformations::EXPAND);
+        let decode_err = |e: ::png::DecodingError| Error::Encoding(format!("{}: {}", path, e));
+        let (info, mut reader) = decoder.read_info().map_err(decode_err)?;
+        let mut buf = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buf).map_err(decode_err)?;
+
+        let (width, height) = (info.width as usize, info.height as usize);
+        let channels = match info.color_type {
+            ColorType::Grayscale => 1,
+            ColorType::GrayscaleAlpha => 2,
+            ColorType::RGB | ColorType::Indexed => 3,
+            ColorType::RGBA => 4
+        };
+        let bytes = info.line_size / (width * channels).max(1);
+        let max = if bytes == 2 {65535.} else {255.};
+        let sample = |i: usize| {
+            let v = if bytes == 2 {
+                (buf[i * 2] as u32) << 8 | buf[i * 2 + 1] as u32
+            } else {
+                buf[i] as u32
+            };
+            let v = v as f64 / max;
+            if srgb {srgb_decode(v)} else {v}
+        };
+
+        let mut texels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = y * info.line_size / bytes;
+            for x in 0..width {
+                let i = row + x * channels;
+                texels.push(if channels < 3 {
+                    let g = sample(i);
+                    Vec3::new(g, g, g)
+                } else {
+                    Vec3::new(sample(i), sample(i + 1), sample(i + 2))
+                });
+            }
+        }
+        Texture::new(width, height, texels)
+    }
+
+    pub fn get(&self, x: i64, y: i64, wrap: Wrap) -> Vec3<f64> {
+        let (w, h) = (self.width as i64, self.height as i64);
+        let (x, y) = match wrap {
+            Wrap::Repeat => (((x % w) + w) % w, ((y % h) + h) % h),
+            Wrap::Clamp => (x.max(0).min(w - 1), y.max(0).min(h - 1))
+        };
+        self.texels[(y * w + x) as usize]
+    }
+
+    // Bilinear lookup, v = 0 being the top row
+    pub fn sample(&self, u: f64, v: f64, wrap: Wrap) -> Vec3<f64> {
+        let x = u * self.width as f64 - 0.5;
+        let y = v * self.height as f64 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.get(x0, y0, wrap).lerp(self.get(x0 + 1, y0, wrap), fx);
+        let bottom = self.get(x0, y0 + 1, wrap).lerp(self.get(x0 + 1, y0 + 1, wrap), fx);
+        top.lerp(bottom, fy)
+    }
+}
+
+// How image textures are filtered over a footprint
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filtering {
+    // Finest level only, ignoring the footprint
+    Bilinear,
+    // Between the two levels closest to the footprint's width
+    Trilinear,
+    // Elliptically weighted average over the footprint, sharper for
+    // surfaces seen at grazing angles
+    Ewa
+}
+
+impl FromStr for Filtering {
+    type Err = ParseError;
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "bilinear" => Ok(Filtering::Bilinear),
+            "trilinear" => Ok(Filtering::Trilinear),
+            "ewa" => Ok(Filtering::Ewa),
+            _ => Err(ParseError::new("Unknown texture filtering, expected bilinear, trilinear \
+                                      or ewa", s))
+        }
+    }
+}
+
+// Bounds the eccentricity of EWA footprints, and so the texels averaged
+const MAX_ANISOTROPY: f64 = 8.;
+
+// Pyramid of textures each half the size of the previous one, down to a
+// single texel
+#[derive(Clone, Debug)]
+pub struct MipMap {
+    pub levels: Vec<Texture>
+}
+
+impl MipMap {
+    pub fn new(texture: Texture) -> Self {
+        let mut levels = vec![texture];
+        loop {
+            let next = {
+                let t = &levels[levels.len() - 1];
+                if t.width <= 1 && t.height <= 1 {
+                    break;
+                }
+                let (w, h) = (t.width.div_ceil(2), t.height.div_ceil(2));
+                let mut texels = Vec::with_capacity(w * h);
+                for y in 0..h as i64 {
+                    for x in 0..w as i64 {
+                        let sum = t.get(2 * x, 2 * y, Wrap::Clamp) +
+                            t.get(2 * x + 1, 2 * y, Wrap::Clamp) +
+                            t.get(2 * x, 2 * y + 1, Wrap::Clamp) +
+                            t.get(2 * x + 1, 2 * y + 1, Wrap::Clamp);
+                        texels.push(sum * 0.25);
+                    }
+                }
+                Texture {width: w, height: h, texels}
+            };
+            levels.push(next);
+        }
+        MipMap {levels}
+    }
+
+    // Level whose texels are `width` wide in texture coordinates
+    fn level_of(&self, width: f64) -> f64 {
+        let size = self.levels[0].width.max(self.levels[0].height) as f64;
+        (width * size).max(1e-12).log2().max(0.).min((self.levels.len() - 1) as f64)
+    }
+
+    fn trilinear(&self, u: f64, v: f64, width: f64, wrap: Wrap) -> Vec3<f64> {
+        let level = self.level_of(width);
+        let l = level.floor() as usize;
+        let fine = self.levels[l].sample(u, v, wrap);
+        if l + 1 >= self.levels.len() {
+            return fine;
+        }
+        fine.lerp(self.levels[l + 1].sample(u, v, wrap), level - l as f64)
+    }
+
+    // Gaussian weighted average of the texels of `level` within the ellipse
+    // with axes `d0` and `d1`, see Heckbert's "Fundamentals of Texture
+    // Mapping and Image Warping"
+    fn ewa(&self, level: usize, u: f64, v: f64, d0: (f64, f64), d1: (f64, f64), wrap: Wrap)
+        -> Vec3<f64> {
+        let t = &self.levels[level];
+        let (w, h) = (t.width as f64, t.height as f64);
+        let (s, tt) = (u * w - 0.5, v * h - 0.5);
+        let (d0, d1) = ((d0.0 * w, d0.1 * h), (d1.0 * w, d1.1 * h));
+
+        // Implicit ellipse a s^2 + b s t + c t^2 = 1, widened by a texel so
+        // it always covers some
+        let mut a = d0.1 * d0.1 + d1.1 * d1.1 + 1.;
+        let mut b = -2. * (d0.0 * d0.1 + d1.0 * d1.1);
+        let mut c = d0.0 * d0.0 + d1.0 * d1.0 + 1.;
+        let f = 1. / (a * c - b * b * 0.25);
+        a *= f;
+        b *= f;
+        c *= f;
+
+        let det = 4. * a * c - b * b;
+        let (du, dv) = (2. * (det * c).sqrt() / det, 2. * (a * det).sqrt() / det);
+        let alpha = 2.;
+        let (mut sum, mut total) = (Vec3::default(), 0.);
+        for y in (tt - dv).ceil() as i64..(tt + dv).floor() as i64 + 1 {
+            let dy = y as f64 - tt;
+            for x in (s - du).ceil() as i64..(s + du).floor() as i64 + 1 {
+                let dx = x as f64 - s;
+                let r2 = a * dx * dx + b * dx * dy + c * dy * dy;
+                if r2 < 1. {
+                    let weight = (-alpha * r2).exp() - (-alpha).exp();
+                    sum = sum + t.get(x, y, wrap) * weight;
+                    total += weight;
+                }
+            }
+        }
+        if total > 0. {sum * (1. / total)} else {t.sample(u, v, wrap)}
+    }
+
+    // `duv` holds the derivatives of (u, v) along the footprint's two axes
+    pub fn sample(&self, u: f64, v: f64, duv: [(f64, f64); 2], filtering: Filtering, wrap: Wrap)
+        -> Vec3<f64> {
+        match filtering {
+            Filtering::Bilinear => self.levels[0].sample(u, v, wrap),
+            Filtering::Trilinear => {
+                let width = 2. * duv[0].0.abs().max(duv[0].1.abs())
+                    .max(duv[1].0.abs()).max(duv[1].1.abs());
+                self.trilinear(u, v, width, wrap)
+            },
+            Filtering::Ewa => {
+                let len = |d: (f64, f64)| (d.0 * d.0 + d.1 * d.1).sqrt();
+                let (mut major, mut minor) = (duv[0], duv[1]);
+                if len(major) < len(minor) {
+                    ::std::mem::swap(&mut major, &mut minor);
+                }
+                let (major_len, mut minor_len) = (len(major), len(minor));
+                // Overly eccentric ellipses are widened, trading sharpness
+                // for a bounded texel count
+                if minor_len * MAX_ANISOTROPY < major_len && minor_len > 0. {
+                    let scale = major_len / (minor_len * MAX_ANISOTROPY);
+                    minor = (minor.0 * scale, minor.1 * scale);
+                    minor_len *= scale;
+                }
+                // Under a texel, the few texels in the ellipse would show
+                // through as wobbly edges
+                let size = self.levels[0].width.max(self.levels[0].height) as f64;
+                if minor_len * size < 1. {
+                    return self.levels[0].sample(u, v, wrap);
+                }
+
+                let level = self.level_of(minor_len);
+                let l = level.floor() as usize;
+                let fine = self.ewa(l, u, v, major, minor, wrap);
+                if l + 1 >= self.levels.len() {
+                    return fine;
+                }
+                fine.lerp(self.ewa(l + 1, u, v, major, minor, wrap), level - l as f64)
+            }
+        }
+    }
+}
+
+#[test]
+fn texture_test() {
+    use png::{Encoder, BitDepth};
+
+    let name = format!("raytracer_texture_test_{}.png", ::std::process::id());
+    let path = ::std::env::temp_dir().join(name);
+    let path = path.to_str().unwrap();
+    {
+        // 2x1 16 bit grey, black then white
+        let mut encoder = Encoder::new(File::create(path).unwrap(), 2, 1);
+        encoder.set(ColorType::Grayscale).set(BitDepth::Sixteen);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0, 0, 255, 255]).unwrap();
+    }
+    // Clean up before checking the result so a failed load leaves nothing behind
+    let tex = Texture::load_png(path, false);
+    ::std::fs::remove_file(path).unwrap();
+    let tex = tex.unwrap();
+    assert_eq!((tex.width, tex.height), (2, 1));
+    assert_eq!(tex.texels, vec![Vec3::new(0., 0., 0.), Vec3::new(1., 1., 1.)]);
+
+    // Texel centres are exact, halfway between them is the average
+    assert_eq!(tex.sample(0.25, 0.5, Wrap::Clamp), Vec3::new(0., 0., 0.));
+    assert_eq!(tex.sample(0.5, 0.5, Wrap::Clamp), Vec3::new(0.5, 0.5, 0.5));
+    // Past the right edge, clamping stays white while repeating blends back
+    assert_eq!(tex.sample(1., 0.5, Wrap::Clamp), Vec3::new(1., 1., 1.));
+    assert_eq!(tex.sample(1., 0.5, Wrap::Repeat), Vec3::new(0.5, 0.5, 0.5));
+
+    let mip = MipMap::new(tex);
+    assert_eq!(mip.levels.len(), 2);
+    assert_eq!(mip.levels[1].texels, vec![Vec3::new(0.5, 0.5, 0.5)]);
+    // A footprint covering the whole texture averages it out
+    let wide = [(1., 0.), (0., 1.)];
+    for &f in &[Filtering::Trilinear, Filtering::Ewa] {
+        assert_eq!(mip.sample(0.25, 0.5, wide, f, Wrap::Repeat), Vec3::new(0.5, 0.5, 0.5));
+        assert_eq!(mip.sample(0.25, 0.5, [(0., 0.); 2], f, Wrap::Repeat), Vec3::new(0., 0., 0.));
+    }
+    assert!(Texture::new(2, 2, vec![Vec3::default(); 3]).is_err());
+    assert!(Texture::new(0, 0, Vec::new()).is_err());
+}
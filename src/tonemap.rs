@@ -0,0 +1,133 @@
+use std::str::FromStr;
+use super::error::ParseError;
+use super::vec3::Vec3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operator {
+    // Plain clamp to [0, 1]
+    Clamp,
+    // Applied to luminance so hues are preserved
+    Reinhard,
+    // Narkowicz's fit of the ACES filmic curve
+    Aces,
+    // Uncharted 2 filmic curve
+    Hable
+}
+
+impl FromStr for Operator {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(Operator::Clamp),
+            "reinhard" => Ok(Operator::Reinhard),
+            "aces" => Ok(Operator::Aces),
+            "hable" => Ok(Operator::Hable),
+            _ => Err(ParseError::new("Unknown tone mapping operator, expected one of clamp, \
+                                      reinhard, aces, hable", s))
+        }
+    }
+}
+
+fn luminance(c: Vec3<f64>) -> f64 {
+    c.dot(&Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+fn aces(x: f64) -> f64 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    (x * (a * x + b)) / (x * (c * x + d) + e)
+}
+
+fn hable_partial(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn hable(x: f64) -> f64 {
+    let white = 11.2;
+    let exposure_bias = 2.;
+    hable_partial(x * exposure_bias) / hable_partial(white)
+}
+
+pub fn srgb_encode(c: f64) -> f64 {
+    if c <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1. / 2.4) - 0.055
+    }
+}
+
+pub fn srgb_decode(c: f64) -> f64 {
+    if c <= 0.040_45 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+impl Operator {
+    pub fn apply(&self, c: Vec3<f64>) -> Vec3<f64> {
+        match *self {
+            Operator::Clamp => c,
+            Operator::Reinhard => {
+                let l = luminance(c);
+                if l <= 0. {c} else {c * (1. / (1. + l))}
+            },
+            Operator::Aces => Vec3::new(aces(c.x), aces(c.y), aces(c.z)),
+            Operator::Hable => Vec3::new(hable(c.x), hable(c.y), hable(c.z))
+        }
+    }
+}
+
+// Maps linear radiance to display values in [0, 1], for low dynamic range
+// output
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ToneMapping {
+    // In stops
+    pub exposure: f64,
+    pub operator: Operator,
+    pub srgb: bool
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        ToneMapping {exposure: 0., operator: Operator::Clamp, srgb: true}
+    }
+}
+
+impl ToneMapping {
+    pub fn apply(&self, c: Vec3<f64>) -> Vec3<f64> {
+        let c = self.operator.apply(c.max(&Vec3::default()) * 2f64.powf(self.exposure));
+        let c = c.min(&Vec3::new(1., 1., 1.));
+        if self.srgb {
+            Vec3::new(srgb_encode(c.x), srgb_encode(c.y), srgb_encode(c.z))
+        } else {
+            c
+        }
+    }
+}
+
+#[test]
+fn tonemap_test() {
+    assert_eq!(srgb_encode(0.), 0.);
+    assert!((srgb_encode(1.) - 1.).abs() < 1e-12);
+    // Linear segment near black
+    assert_eq!(srgb_encode(0.003), 12.92 * 0.003);
+    assert!((srgb_decode(srgb_encode(0.5)) - 0.5).abs() < 1e-12);
+
+    for op in &[Operator::Clamp, Operator::Reinhard, Operator::Aces, Operator::Hable] {
+        assert!(op.apply(Vec3::default()).x.abs() < 1e-12);
+        let mut last = 0.;
+        for i in 1..100 {
+            let x = op.apply(Vec3::new(1., 1., 1.) * (i as f64 * 0.1)).x;
+            assert!(x > last);
+            last = x;
+        }
+    }
+
+    // One stop doubles linear output
+    let tm = |exposure| ToneMapping {exposure, operator: Operator::Clamp, srgb: false}
+        .apply(Vec3::new(0.1, 0.2, 0.3));
+    let (base, up, down) = (tm(0.), tm(1.), tm(-2.));
+    assert_eq!((up.x, up.y, up.z), (base.x * 2., base.y * 2., base.z * 2.));
+    assert_eq!((down.x, down.y, down.z), (base.x * 0.25, base.y * 0.25, base.z * 0.25));
+}
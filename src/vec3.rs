@@ -1,5 +1,6 @@
 use std::str::FromStr;
-use std::ops::{Add, Sub, Mul, Neg};
+use super::error::{ParseError, parse_number, column_of};
+use std::ops::{Add, Sub, Mul, Neg, Index};
 
 pub trait Lerp {
     fn lerp(&self, dst: Self, t: f64) -> Self
@@ -30,6 +31,7 @@ impl Lerp for f64 {
     }
 }
 
+#[derive(PartialEq, Debug)]
 pub struct Vec3<T> {
     pub x: T,
     pub y: T,
@@ -44,26 +46,21 @@ impl<T> Default for Vec3<T> where T: Default {
 
 impl Clone for Vec3<f64> {
     fn clone(&self) -> Self {
-        Vec3::<f64>::new(self.x, self.y, self.z)
-    }
-
-    fn clone_from(&mut self, source: &Self) {
-        self.x = source.x;
-        self.y = source.y;
-        self.z = source.z;
+        *self
     }
 }
 
 impl FromStr for Vec3<f64> {
-    type Err = ();
+    type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let tokens: Vec<&str> = s.split(' ').collect();
+        let tokens: Vec<&str> = s.split_whitespace().collect();
         if tokens.len() != 3 {
-            return Err(());
+            return Err(ParseError::new("Expected 3 components", s.trim())
+                       .at_column(column_of(s, s.trim())));
         }
-        let x = f64::from_str(tokens[0].trim()).or_else(|_| Err(()))?;
-        let y = f64::from_str(tokens[1].trim()).or_else(|_| Err(()))?;
-        let z = f64::from_str(tokens[2].trim()).or_else(|_| Err(()))?;
+        let x = parse_number(s, tokens[0], "number")?;
+        let y = parse_number(s, tokens[1], "number")?;
+        let z = parse_number(s, tokens[2], "number")?;
         Ok(Vec3::new(x, y, z))
     }
 }
@@ -108,6 +105,14 @@ impl Vec3<f64> {
                   self.x * rhs.y - self.y * rhs.x)
     }
 
+    pub fn min(&self, rhs: &Self) -> Self {
+        Vec3::new(self.x.min(rhs.x), self.y.min(rhs.y), self.z.min(rhs.z))
+    }
+
+    pub fn max(&self, rhs: &Self) -> Self {
+        Vec3::new(self.x.max(rhs.x), self.y.max(rhs.y), self.z.max(rhs.z))
+    }
+
     pub fn abs(&self) -> Self {
         Vec3::new(self.x.abs(), self.y.abs(), self.z.abs())
     }
@@ -152,6 +157,18 @@ impl Lerp for Vec3<f64> {
     }
 }
 
+impl<T> Index<usize> for Vec3<T> {
+    type Output = T;
+    fn index(&self, axis: usize) -> &T {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 index out of bounds: {}", axis)
+        }
+    }
+}
+
 impl<T, O> Neg for Vec3<T> where T: Neg<Output=O> {
     type Output = Vec3<O>;
     fn neg(self) -> Self::Output {
@@ -200,6 +217,32 @@ pub fn solve_quadratic(eq: &Vec3<f64>) -> Option<(f64, f64)> {
     Some((x0, x1))
 }
 
+// Two unit vectors orthogonal to `n` and to each other
+pub fn basis(n: Vec3<f64>) -> (Vec3<f64>, Vec3<f64>) {
+    let sign = 1f64.copysign(n.z);
+    let a = -1. / (sign + n.z);
+    let b = n.x * n.y * a;
+    (Vec3::new(1. + sign * n.x * n.x * a, sign * b, -sign * n.x),
+     Vec3::new(b, sign + n.y * n.y * a, -n.y))
+}
+
+pub fn reflect(dir: Vec3<f64>, n: Vec3<f64>) -> Vec3<f64> {
+    let mut r = dir - n * 2. * dir.dot(&n);
+    *r.normalize()
+}
+
+// `n` faces `dir`, `eta` is the ratio of the indices of refraction. None on
+// total internal reflection.
+pub fn refract(dir: Vec3<f64>, n: Vec3<f64>, eta: f64) -> Option<Vec3<f64>> {
+    let cosi = -n.dot(&dir);
+    let k = 1. - eta * eta * (1. - cosi * cosi);
+    if k < 0. {
+        return None;
+    }
+    let mut r = dir * eta + n * (eta * cosi - k.sqrt());
+    Some(*r.normalize())
+}
+
 #[test]
 fn quadratic_test() {
     let (x0, x1) = match solve_quadratic(&Vec3::new(1., 1., -20.)) {